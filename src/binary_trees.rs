@@ -9,9 +9,13 @@
 extern crate typed_arena;
 extern crate rayon;
 
+use std::io::{self, Write};
 use typed_arena::Arena;
+use rayon::Configuration;
 use rayon::prelude::*;
 
+include!("common/alloc.rs");
+
 struct Tree<'a> {
     children: Option<(&'a Tree<'a>, &'a Tree<'a>)>,
     item: i32,
@@ -46,10 +50,47 @@ fn inner(depth: i32, iterations: i32) -> String {
     format!("{}\t trees of depth {}\t check: {}", iterations * 2, depth, chk)
 }
 
-fn main() {
-    let n = std::env::args().nth(1)
+include!("common/main.rs");
+
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: binary_trees [--threads N] [DEPTH]
+
+Build binary trees up to DEPTH levels deep (default 10), walk them, and print
+their checks.
+
+  --threads N  use N threads rather than one for each CPU
+  -h, --help   print this message
+  --version    print how this was built
+";
+
+fn run() -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut n = 10;
+    // RAYON_NUM_THREADS sets the default, as for reverse_complement.
+    let mut threads = std::env::var("RAYON_NUM_THREADS").ok()
         .and_then(|n| n.parse().ok())
-        .unwrap_or(10);
+        .filter(|&n| n > 0);
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--threads") => {
+                threads = Some(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid_input("--threads needs a positive number".into()))?);
+            }
+            Some("-h") | Some("--help") => return out.write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut out),
+            _ => n = arg.to_str().and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_input(format!("unknown argument {:?}; see --help", arg)))?,
+        }
+    }
+    if let Some(n) = threads {
+        rayon::initialize(Configuration::new().set_num_threads(n)).unwrap();
+    }
     let min_depth = 4;
     let max_depth = if min_depth + 2 > n { min_depth + 2 } else { n };
 
@@ -57,7 +98,7 @@ fn main() {
         let arena = Arena::new();
         let depth = max_depth + 1;
         let tree = bottom_up_tree(&arena, 0, depth);
-        println!("stretch tree of depth {}\t check: {}", depth, item_check(tree));
+        writeln!(out, "stretch tree of depth {}\t check: {}", depth, item_check(tree))?;
     }
 
     let long_lived_arena = Arena::new();
@@ -70,8 +111,8 @@ fn main() {
         }).collect::<Vec<_>>();
 
     for message in messages {
-        println!("{}", message);
+        writeln!(out, "{}", message)?;
     }
 
-    println!("long lived tree of depth {}\t check: {}", max_depth, item_check(long_lived_tree));
+    writeln!(out, "long lived tree of depth {}\t check: {}", max_depth, item_check(long_lived_tree))
 }
@@ -4,18 +4,23 @@
 // contributed by the Rust Project Developers
 // contributed by TeXitoi
 
+
 use self::Color::{Red, Yellow, Blue};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::fmt;
+use std::io::{self, Write};
 use std::thread::spawn;
 
-fn print_complements() {
+include!("common/alloc.rs");
+
+fn print_complements(out: &mut dyn Write) -> io::Result<()> {
     let all = [Blue, Red, Yellow];
     for aa in all.iter() {
         for bb in all.iter() {
-            println!("{} + {} -> {}", *aa, *bb, transform(*aa, *bb));
+            writeln!(out, "{} + {} -> {}", *aa, *bb, transform(*aa, *bb))?;
         }
     }
+    Ok(())
 }
 
 #[derive(Clone, Copy)]
@@ -136,7 +141,7 @@ fn creature(
     to_rendezvous_log.send(report).unwrap();
 }
 
-fn rendezvous(nn: usize, set: Vec<Color>) {
+fn rendezvous(nn: usize, set: Vec<Color>, out: &mut dyn Write) -> io::Result<()> {
     // these ports will allow us to hear from the creatures
     let (to_rendezvous, from_creatures) = channel::<CreatureInfo>();
 
@@ -174,33 +179,54 @@ fn rendezvous(nn: usize, set: Vec<Color>) {
         to_creature[snd_creature.name].send(fst_creature).unwrap();
     }
 
-    // tell each creature to stop
+    // tell each creature to stop, and wait for all their stats before
+    // printing anything, so none are left sending if that fails
     drop(to_creature);
+    drop(to_rendezvous_log);
+    let reports: Vec<String> = from_creatures_log.iter().collect();
 
     // print each color in the set
-    println!("{}", show_color_list(set));
+    writeln!(out, "{}", show_color_list(set))?;
 
     // print each creature's stats
-    drop(to_rendezvous_log);
-    for rep in from_creatures_log.iter() {
-        println!("{}", rep);
+    for rep in reports {
+        writeln!(out, "{}", rep)?;
     }
 
     // print the total number of creatures met
-    println!("{}\n", Number(creatures_met));
+    writeln!(out, "{}\n", Number(creatures_met))
 }
 
-fn main() {
-    let nn = std::env::args_os().nth(1)
-        .and_then(|s| s.into_string().ok())
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(600);
+include!("common/main.rs");
+
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: chameneos_redux [N]
+
+Print how chameneos change colour when they meet, then have a group of three
+and a group of ten meet N times in all (default 600), and print how many
+meetings each creature had.
+";
+
+fn run() -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut nn = 600;
+    for arg in std::env::args_os().skip(1) {
+        match arg.to_str() {
+            Some("-h") | Some("--help") => return out.write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut out),
+            _ => nn = arg.to_str().and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_input(format!("unknown argument {:?}; see --help", arg)))?,
+        }
+    }
 
-    print_complements();
-    println!("");
+    print_complements(&mut out)?;
+    writeln!(out, "")?;
 
-    rendezvous(nn, vec!(Blue, Red, Yellow));
+    rendezvous(nn, vec!(Blue, Red, Yellow), &mut out)?;
 
     rendezvous(nn,
-        vec!(Blue, Red, Yellow, Red, Yellow, Blue, Red, Yellow, Red, Blue));
+        vec!(Blue, Red, Yellow, Red, Yellow, Blue, Red, Yellow, Red, Blue),
+        &mut out)
 }
@@ -0,0 +1,19 @@
+// The allocator for each program. Built with `make FEATURES=alloc-jemalloc` or
+// `alloc-mimalloc`, it's that one rather than the system's, which matters most
+// for binary_trees, as that benchmark is mostly allocation. Each program
+// includes this at the top of its crate with `include!("common/alloc.rs")`.
+#[cfg(all(feature = "alloc-jemalloc", feature = "alloc-mimalloc"))]
+compile_error!("only one of the alloc-jemalloc and alloc-mimalloc features can be enabled");
+
+#[cfg(feature = "alloc-jemalloc")]
+extern crate jemallocator;
+#[cfg(feature = "alloc-mimalloc")]
+extern crate mimalloc;
+
+#[cfg(feature = "alloc-jemalloc")]
+#[global_allocator]
+static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+#[cfg(feature = "alloc-mimalloc")]
+#[global_allocator]
+static ALLOC: mimalloc::MiMalloc = mimalloc::MiMalloc;
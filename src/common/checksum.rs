@@ -0,0 +1,72 @@
+/// A quick, non-cryptographic hash of everything written to it, for
+/// `--checksum`. Bytes are hashed in blocks whatever the size of each write,
+/// so the result only depends on the output itself, and fasta and
+/// reverse_complement share this so that their checksums can be compared.
+struct Checksum {
+    /// One hash for each word of a block, so they can be updated in parallel.
+    lanes: [u64; 4],
+    len: u64,
+    /// Bytes left over from the last write, which don't make a whole block.
+    tail: [u8; 32],
+    tail_len: usize,
+}
+
+impl Checksum {
+    fn new() -> Checksum {
+        Checksum { lanes: [1, 2, 3, 4], len: 0, tail: [0; 32], tail_len: 0 }
+    }
+
+    fn add(&mut self, block: &[u8]) {
+        for (lane, bytes) in self.lanes.iter_mut().zip(block.chunks_exact(8)) {
+            let mut word = [0; 8];
+            word.copy_from_slice(bytes);
+            let word = u64::from_le_bytes(word);
+            *lane = (lane.rotate_left(5) ^ word).wrapping_mul(0x517c_c1b7_2722_0a95);
+        }
+    }
+
+    fn finish(mut self) -> u64 {
+        let mut last = [0; 32];
+        last[..self.tail_len].copy_from_slice(&self.tail[..self.tail_len]);
+        last[24..].copy_from_slice(&self.len.to_le_bytes());
+        self.add(&last);
+        self.lanes.iter().fold(0, |hash, &lane| {
+            (hash.rotate_left(5) ^ lane).wrapping_mul(0x517c_c1b7_2722_0a95)
+        })
+    }
+}
+
+impl std::io::Write for Checksum {
+    fn write(&mut self, mut buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len();
+        self.len += len as u64;
+        if self.tail_len > 0 {
+            let n = std::cmp::min(32 - self.tail_len, buf.len());
+            self.tail[self.tail_len..self.tail_len + n].copy_from_slice(&buf[..n]);
+            self.tail_len += n;
+            buf = &buf[n..];
+            if self.tail_len < 32 {
+                return Ok(len)
+            }
+            let tail = self.tail;
+            self.add(&tail);
+            self.tail_len = 0;
+        }
+        let mut blocks = buf.chunks_exact(32);
+        for block in &mut blocks {
+            self.add(block);
+        }
+        let rest = blocks.remainder();
+        self.tail[..rest.len()].copy_from_slice(rest);
+        self.tail_len = rest.len();
+        Ok(len)
+    }
+
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice]) -> std::io::Result<usize> {
+        bufs.iter().map(|buf| self.write(buf)).sum()
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+/// The IUPAC nucleotide codes, each with its complement: the code for the
+/// bases that pair with the ones it stands for. Apart from U, which pairs
+/// like T, complementing twice gives back the same code. These are also the
+/// bases reverse_complement's `--strict` accepts, in either case.
+const IUPAC_COMPLEMENTS: [(u8, u8); 16] = [
+    (b'A', b'T'), (b'C', b'G'), (b'G', b'C'), (b'T', b'A'), (b'U', b'A'),
+    (b'M', b'K'), (b'R', b'Y'), (b'W', b'W'), (b'S', b'S'), (b'Y', b'R'),
+    (b'K', b'M'), (b'V', b'B'), (b'H', b'D'), (b'D', b'H'), (b'B', b'V'),
+    (b'N', b'N'),
+];
+
+/// The uppercase complement of every byte that is a code in either case, and
+/// every other byte unchanged, as reverse_complement prints them by default.
+const IUPAC_TABLE: [u8; 256] = iupac_table();
+
+const fn iupac_table() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < table.len() {
+        table[i] = i as u8;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < IUPAC_COMPLEMENTS.len() {
+        let (code, complement) = IUPAC_COMPLEMENTS[i];
+        table[code as usize] = complement;
+        table[code.to_ascii_lowercase() as usize] = complement;
+        i += 1;
+    }
+    table
+}
@@ -0,0 +1,31 @@
+// The main function, error reporting and --version that every program shares.
+// Each one does its work in `run`, and whatever error that returns is printed
+// on one line after the program's name, with exit status 1. The name is
+// `module_path!()` here at the top of the crate, which rustc takes from the
+// source file's name.
+
+fn main() {
+    match run() {
+        // Stdout was closed early, as by `| head`.
+        Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            eprintln!("{}: {}", module_path!(), e);
+            std::process::exit(1);
+        }
+        Ok(()) => {}
+    }
+}
+
+fn invalid_input(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+// What --version prints after the name: the revision, compiler, features and
+// flags this was built with, which the Makefile gives rustc as BUILD_INFO.
+const BUILD_INFO: Option<&str> = option_env!("BUILD_INFO");
+
+/// Print the program's name and `BUILD_INFO` to `out`, for `--version`.
+fn write_version(out: &mut dyn std::io::Write) -> std::io::Result<()> {
+    let info = BUILD_INFO.unwrap_or("built without the Makefile");
+    writeln!(out, "{}\n{}", module_path!(), info)
+}
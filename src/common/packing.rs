@@ -0,0 +1,84 @@
+/// A k-mer of up to 32 bases, packed 2 bits to a base in a `u64` with the
+/// last base in the low bits. Bytes are encoded one at a time with
+/// `Code::encode`, which gives `Code::OTHER` for anything that isn't a base;
+/// those have to be split out before packing, as they don't fit in 2 bits.
+#[derive(Hash, PartialEq, PartialOrd, Ord, Eq, Clone, Copy)]
+struct Code(u64);
+impl Code {
+    fn push(&mut self, c: u8, mask: u64) {
+        self.0 <<= 2;
+        self.0 |= c as u64;
+        self.0 &= mask;
+    }
+    fn from_str(s: &str) -> Code {
+        let mask = Code::make_mask(s.len());
+        let mut res = Code(0);
+        for c in s.as_bytes() {
+            res.push(Code::encode(*c), mask);
+        }
+        res
+    }
+    fn to_string(&self, frame: usize) -> String {
+        let mut res = vec![];
+        let mut code = self.0;
+        for _ in 0..frame {
+            let c = match code as u8 & 0b11 {
+                c if c == Code::encode(b'A') => b'A',
+                c if c == Code::encode(b'T') => b'T',
+                c if c == Code::encode(b'G') => b'G',
+                c if c == Code::encode(b'C') => b'C',
+                _ => unreachable!(),
+            };
+            res.push(c);
+            code >>= 2;
+        }
+        res.reverse();
+        String::from_utf8(res).unwrap()
+    }
+    fn make_mask(frame: usize) -> u64 {
+        // A 32-mer takes the whole word.
+        if frame >= 32 { !0 } else { (1u64 << (2 * frame)) - 1 }
+    }
+    /// Code used for anything that isn't a base, like N.
+    const OTHER: u8 = 4;
+
+    /// The 2-bit code for a base, in either case, or `Code::OTHER`.
+    fn encode(c: u8) -> u8 {
+        match c | 0x20 {
+            b'a' | b'c' | b'g' | b't' => (c & 0b110) >> 1,
+            _ => Code::OTHER,
+        }
+    }
+}
+
+/// The rolling k-mers of `frame` bases in already-encoded input, one for each
+/// position a whole k-mer ends at.
+struct Iter<'a> {
+    iter: std::slice::Iter<'a, u8>,
+    code: Code,
+    mask: u64,
+}
+impl<'a> Iter<'a> {
+    fn new(input: &[u8], frame: usize) -> Iter {
+        let mut iter = input.iter();
+        let mut code = Code(0);
+        let mask = Code::make_mask(frame);
+        for c in iter.by_ref().take(frame - 1) {
+            code.push(*c, mask);
+        }
+        Iter {
+            iter: iter,
+            code: code,
+            mask: mask,
+        }
+    }
+}
+impl<'a> Iterator for Iter<'a> {
+    type Item = Code;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|&c| {
+            self.code.push(c, self.mask);
+            self.code
+        })
+    }
+}
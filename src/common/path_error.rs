@@ -0,0 +1,4 @@
+/// `e`, with `path` in front of its message.
+fn path_error(path: &std::path::Path, e: std::io::Error) -> std::io::Error {
+    std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,49 @@
+/// How often `Progress` updates its line.
+#[cfg(feature = "progress")]
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// For `--progress`, a count of the bytes of output so far, kept up to date on
+/// stderr, with a guess at how long the rest will take if the output's size is
+/// known.
+#[cfg(feature = "progress")]
+struct Progress {
+    done: u64,
+    /// About how big the output will be, or 0 if that's unknown.
+    total: u64,
+    start: std::time::Instant,
+    shown: std::time::Instant,
+}
+
+#[cfg(feature = "progress")]
+impl Progress {
+    fn new(total: u64) -> Progress {
+        let now = std::time::Instant::now();
+        Progress { done: 0, total: total, start: now, shown: now }
+    }
+
+    fn add(&mut self, len: usize) {
+        self.done += len as u64;
+        if self.shown.elapsed() >= PROGRESS_INTERVAL {
+            self.shown = std::time::Instant::now();
+            self.show();
+        }
+    }
+
+    fn show(&self) {
+        let secs = self.start.elapsed().as_secs_f64();
+        let mb = |bytes: u64| bytes as f64 / 1e6;
+        if self.done > 0 && self.done < self.total {
+            // Assume the rest goes as fast as what's been done so far.
+            let left = secs * (self.total - self.done) as f64 / self.done as f64;
+            eprint!("\r{:.1} of {:.1} MB, about {:.0}s left   ",
+                    mb(self.done), mb(self.total), left);
+        } else {
+            eprint!("\r{:.1} MB in {:.0}s   ", mb(self.done), secs);
+        }
+    }
+
+    fn finish(&self) {
+        self.show();
+        eprintln!();
+    }
+}
@@ -8,15 +8,23 @@
 extern crate rayon;
 
 use std::{cmp, mem};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use rayon::Configuration;
 use rayon::prelude::*;
 
+include!("common/alloc.rs");
+
 // This value controls the preferred maximum number of  blocks the workload is
 // broken up into. The actual value may be one higher (if the number of
 // permutations doesn't divide exactly by this value) or might be set to 1 if
-// the number of permutations is lower than this value.
+// the number of permutations is lower than this value. The blocks don't depend
+// on the number of threads, so neither do the results.
 const NUM_BLOCKS: u32 = 24;
 
-fn fannkuch(n: i32) -> (i32, i32) {
+/// Returns the checksum and the maximum number of flips. With `progress`, the
+/// number of blocks done so far is kept up to date on stderr.
+fn fannkuch(n: i32, progress: bool) -> (i32, i32) {
     // Precompute a table a factorials to reuse all over the place.
     let mut factorials = [1; 16];
     for i in 1..n as usize + 1 {
@@ -35,8 +43,17 @@ fn fannkuch(n: i32) -> (i32, i32) {
          perm_max / NUM_BLOCKS)
     };
 
+    let blocks_done = AtomicUsize::new(0);
+    let finish_block = |result| {
+        if progress {
+            let done = blocks_done.fetch_add(1, Ordering::SeqCst) + 1;
+            eprint!("\r{}/{} blocks", done, num_blocks);
+        }
+        result
+    };
+
     // Compute the `checksum` and `maxflips` for each block in parallel.
-    (0..num_blocks).into_par_iter().map(|i_block| {
+    let result = (0..num_blocks).into_par_iter().map(|i_block| {
         let initial = i_block * block_size;
         let mut count = [0i32; 16];
         let mut temp = [0i32; 16];
@@ -110,7 +127,7 @@ fn fannkuch(n: i32) -> (i32, i32) {
             // the `checksum` and `maxflips` values which get reduced across
             // blocks in parallel by `rayon`.
             if permutation_index >= last_permutation_in_block {
-                return (checksum, maxflips);
+                return finish_block((checksum, maxflips));
             }
             permutation_index += 1;
 
@@ -132,14 +149,60 @@ fn fannkuch(n: i32) -> (i32, i32) {
             count[i] += 1;
         }
     }).reduce(|| (0, 0),
-              |(cs1, mf1), (cs2, mf2)| (cs1 + cs2, cmp::max(mf1, mf2)))
+              |(cs1, mf1), (cs2, mf2)| (cs1 + cs2, cmp::max(mf1, mf2)));
+    if progress {
+        eprintln!();
+    }
+    result
 }
 
-fn main() {
-    let n = std::env::args().nth(1)
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: fannkuch_redux [--progress] [--threads N] [N]
+
+Flip pancakes in every permutation of N items (from 1 to 15, default 7), and
+print a checksum and the most flips any permutation took.
+
+  --progress   show how many blocks of permutations are done on stderr
+  --threads N  use N threads rather than one for each CPU
+  -h, --help   print this message
+  --version    print how this was built
+";
+
+include!("common/main.rs");
+
+fn run() -> io::Result<()> {
+    let mut n = 7;
+    let mut progress = false;
+    // RAYON_NUM_THREADS sets the default, as for reverse_complement.
+    let mut threads = std::env::var("RAYON_NUM_THREADS").ok()
         .and_then(|n| n.parse().ok())
-        .unwrap_or(7);
+        .filter(|&n| n > 0);
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--progress") => progress = true,
+            Some("--threads") => {
+                threads = Some(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid_input("--threads needs a positive number".into()))?);
+            }
+            Some("-h") | Some("--help") => return io::stdout().write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut io::stdout()),
+            _ => n = arg.to_str().and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_input(format!("unknown argument {:?}; see --help", arg)))?,
+        }
+    }
 
-    let (checksum, maxflips) = fannkuch(n);
-    println!("{}\nPfannkuchen({}) = {}", checksum, n, maxflips);
+    // Permutations are counted in a u32, which 16! is past.
+    if n < 1 || n > 15 {
+        return Err(invalid_input(format!("N must be from 1 to 15, not {}", n)));
+    }
+    if let Some(n) = threads {
+        rayon::initialize(Configuration::new().set_num_threads(n)).unwrap();
+    }
+    let (checksum, maxflips) = fannkuch(n, progress);
+    writeln!(io::stdout(), "{}\nPfannkuchen({}) = {}", checksum, n, maxflips)
 }
@@ -4,187 +4,898 @@
 // contributed by the Rust Project Developers
 // contributed by TeXitoi
 // multi-threaded version contributed by Alisdair Owens
+extern crate flate2;
 extern crate num_cpus;
+#[cfg(not(feature = "std-threads"))]
+extern crate rayon;
+extern crate toml;
+
+#[cfg(all(target_os = "wasi", not(feature = "std-threads")))]
+compile_error!("wasi has no threads for rayon, so build with the std-threads feature");
 
 use std::cmp::min;
+use std::fs::File;
 use std::io;
-use std::io::{Write, BufWriter, ErrorKind};
-use std::sync::{Mutex,Arc};
+use std::io::{Read, Write, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use std::thread;
+use flate2::write::GzEncoder;
+#[cfg(not(feature = "std-threads"))]
+use rayon::prelude::*;
+#[cfg(not(feature = "std-threads"))]
+use rayon::{Configuration, ThreadPool};
+#[cfg(target_arch = "x86")]
+use std::arch::x86 as arch;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64 as arch;
+
+include!("common/alloc.rs");
 
 const LINE_LENGTH: usize = 60;
 const IM: u32 = 139968;
 const LINES: usize = 1024;
 const BLKLEN: usize = LINE_LENGTH * LINES;
+/// Length of each record with `--reads`, unless `--read-length` says otherwise.
+const DEFAULT_READ_LEN: usize = 150;
+/// Number of blocks to generate at once for each CPU.
+const BLOCKS_PER_THREAD: usize = 4;
 
-struct MyStdOut {
-    thread_count: u16,
-    next_thread_num: u16,
-    stdout: io::Stdout,
+/// The benchmark's linear congruential generator.
+#[derive(Clone, Copy)]
+struct LcgRng {
+    last: u32,
 }
 
-struct MyRandom {
-    last: u32,
-    count: usize,
-    thread_count: u16,
-    next_thread_num: u16,
+impl LcgRng {
+    const IA: u32 = 3877;
+    const IC: u32 = 29573;
+
+    fn new(seed: u32) -> LcgRng {
+        LcgRng { last: seed }
+    }
+
+    fn normalize(p: f64) -> u32 {(p * IM as f64).floor() as u32}
+
+    fn gen(&mut self) -> u32 {
+        self.last = (self.last * LcgRng::IA + LcgRng::IC) % IM;
+        self.last
+    }
+
+    /// The `(a, c)` that take the generator `k` steps at once, from x to
+    /// (a * x + c) % IM, worked out in O(log k) time.
+    fn jump(mut k: usize) -> (u64, u64) {
+        // Each step maps x to (a * x + c) % IM, and so does any number of
+        // them. Compose the steps for each set bit of `k`, squaring as we go.
+        let im = IM as u64;
+        let (mut a, mut c) = (1, 0);
+        let (mut step_a, mut step_c) = (LcgRng::IA as u64, LcgRng::IC as u64);
+        while k > 0 {
+            if k & 1 == 1 {
+                a = a * step_a % im;
+                c = (c * step_a + step_c) % im;
+            }
+            step_c = (step_c * step_a + step_c) % im;
+            step_a = step_a * step_a % im;
+            k >>= 1;
+        }
+        (a, c)
+    }
+
+    /// Advance the generator by `k` steps, so that each block of a sequence
+    /// can start from its own state without generating everything before it.
+    fn advance(&mut self, k: usize) {
+        let (a, c) = LcgRng::jump(k);
+        self.last = ((a * self.last as u64 + c) % IM as u64) as u32;
+    }
+}
+
+/// An endless stream of the generator's numbers, from 0 up to IM.
+impl Iterator for LcgRng {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        Some(self.gen())
+    }
 }
 
-impl MyRandom {
-    fn new(count: usize, thread_count: u16) -> MyRandom {
-        MyRandom {
-            last: 42,
-            count: count,
-            thread_count: thread_count,
-            next_thread_num: 0
+/// Number of interleaved generators in `fill_random`.
+const LANES: usize = 8;
+
+/// Fill `out` with the bases for the next `out.len()` random numbers from
+/// `rng`. A single generator can't go any faster than one multiply and
+/// modulo after another, so this runs LANES of them side by side instead:
+/// lane `i` gives numbers `i`, `i + LANES`, `i + 2 * LANES` and so on, each
+/// jumping LANES steps at a time. The lanes don't depend on each other, so
+/// on x86 they are kept in vector registers and stepped together, four to a
+/// register with AVX or two with SSE2, whichever the CPU has.
+fn fill_random(rng: LcgRng, out: &mut [u8], lookup: &[u8]) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            return unsafe { fill_random_avx(rng, out, lookup) };
+        }
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { fill_random_sse2(rng, out, lookup) };
         }
     }
+    fill_random_scalar(rng, out, lookup)
+}
 
-    fn normalize(p: f32) -> u32 {(p * IM as f32).floor() as u32}
+/// The first number of each lane, and the `(a, c)` that step a lane on.
+fn start_lanes(mut rng: LcgRng) -> ([u32; LANES], u32, u32) {
+    let mut lanes = [0; LANES];
+    for (lane, x) in lanes.iter_mut().zip(&mut rng) {
+        *lane = x;
+    }
+    let (a, c) = LcgRng::jump(LANES);
+    (lanes, a as u32, c as u32)
+}
 
-    fn reset(&mut self, count: usize) {
-        self.next_thread_num = 0;
-        self.count = count;
+/// `fill_random` one lane at a time, for CPUs without the vector version,
+/// and to check that version against.
+fn fill_random_scalar(rng: LcgRng, out: &mut [u8], lookup: &[u8]) {
+    let (mut lanes, a, c) = start_lanes(rng);
+    let (a, c, im) = (a as u64, c as u64, IM as u64);
+    let mut chunks = out.chunks_exact_mut(LANES);
+    for chunk in &mut chunks {
+        for (b, lane) in chunk.iter_mut().zip(&mut lanes) {
+            *b = lookup[*lane as usize];
+            *lane = ((*lane as u64 * a + c) % im) as u32;
+        }
     }
+    for (b, lane) in chunks.into_remainder().iter_mut().zip(&lanes) {
+        *b = lookup[*lane as usize];
+    }
+}
+
+/// `fill_random` with the lanes in SSE2 registers, two to a register.
+///
+/// There is no vector integer modulo, or a 64-bit multiply to do one with,
+/// so the lanes are kept as doubles: a * x + c is below 2^36, so it is
+/// exact, and multiplying it by 1 / IM and truncating gives the quotient.
+/// The product is out by far less than the 1 / IM a remainder other than
+/// zero keeps it from a whole number, and a remainder of zero rounds to the
+/// quotient itself; the tests step every state to be sure.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn fill_random_sse2(rng: LcgRng, out: &mut [u8], lookup: &[u8]) {
+    let (start, a, c) = start_lanes(rng);
+    let mut lanes = [arch::_mm_setzero_pd(); LANES / 2];
+    for (lane, x) in lanes.iter_mut().zip(start.chunks_exact(2)) {
+        *lane = arch::_mm_set_pd(x[1] as f64, x[0] as f64);
+    }
+    let a = arch::_mm_set1_pd(a as f64);
+    let c = arch::_mm_set1_pd(c as f64);
+    let im = arch::_mm_set1_pd(IM as f64);
+    let inv = arch::_mm_set1_pd(1.0 / IM as f64);
+    let mut index = [0i32; LANES];
+    let mut chunks = out.chunks_exact_mut(LANES);
+    for chunk in &mut chunks {
+        for (i, lane) in lanes.iter_mut().enumerate() {
+            let x = arch::_mm_cvttpd_epi32(*lane);
+            arch::_mm_storel_epi64(index[2 * i..].as_mut_ptr() as *mut arch::__m128i, x);
+            let p = arch::_mm_add_pd(arch::_mm_mul_pd(*lane, a), c);
+            let q = arch::_mm_cvtepi32_pd(arch::_mm_cvttpd_epi32(arch::_mm_mul_pd(p, inv)));
+            *lane = arch::_mm_sub_pd(p, arch::_mm_mul_pd(q, im));
+        }
+        for (b, &x) in chunk.iter_mut().zip(&index) {
+            *b = lookup[x as usize];
+        }
+    }
+    for (i, lane) in lanes.iter().enumerate() {
+        let x = arch::_mm_cvttpd_epi32(*lane);
+        arch::_mm_storel_epi64(index[2 * i..].as_mut_ptr() as *mut arch::__m128i, x);
+    }
+    for (b, &x) in chunks.into_remainder().iter_mut().zip(&index) {
+        *b = lookup[x as usize];
+    }
+}
+
+/// `fill_random_sse2` with four lanes to a register.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+unsafe fn fill_random_avx(rng: LcgRng, out: &mut [u8], lookup: &[u8]) {
+    let (start, a, c) = start_lanes(rng);
+    let mut lanes = [arch::_mm256_setzero_pd(); LANES / 4];
+    for (lane, x) in lanes.iter_mut().zip(start.chunks_exact(4)) {
+        *lane = arch::_mm256_set_pd(x[3] as f64, x[2] as f64, x[1] as f64, x[0] as f64);
+    }
+    let a = arch::_mm256_set1_pd(a as f64);
+    let c = arch::_mm256_set1_pd(c as f64);
+    let im = arch::_mm256_set1_pd(IM as f64);
+    let inv = arch::_mm256_set1_pd(1.0 / IM as f64);
+    let mut index = [0i32; LANES];
+    let mut chunks = out.chunks_exact_mut(LANES);
+    for chunk in &mut chunks {
+        for (i, lane) in lanes.iter_mut().enumerate() {
+            let x = arch::_mm256_cvttpd_epi32(*lane);
+            arch::_mm_storeu_si128(index[4 * i..].as_mut_ptr() as *mut arch::__m128i, x);
+            let p = arch::_mm256_add_pd(arch::_mm256_mul_pd(*lane, a), c);
+            let q = arch::_mm256_cvttpd_epi32(arch::_mm256_mul_pd(p, inv));
+            let q = arch::_mm256_cvtepi32_pd(q);
+            *lane = arch::_mm256_sub_pd(p, arch::_mm256_mul_pd(q, im));
+        }
+        for (b, &x) in chunk.iter_mut().zip(&index) {
+            *b = lookup[x as usize];
+        }
+    }
+    for (i, lane) in lanes.iter().enumerate() {
+        let x = arch::_mm256_cvttpd_epi32(*lane);
+        arch::_mm_storeu_si128(index[4 * i..].as_mut_ptr() as *mut arch::__m128i, x);
+    }
+    for (b, &x) in chunks.into_remainder().iter_mut().zip(&index) {
+        *b = lookup[x as usize];
+    }
+}
 
-    fn gen(&mut self, buf: &mut [u32], cur_thread: u16) -> Result<usize,()> {
+/// The output, and how what is written to it is encoded.
+enum Output {
+    Plain(Box<dyn Write + Send>),
+    Gzip(GzEncoder<Box<dyn Write + Send>>),
+    /// Only a checksum of everything is written, at the end.
+    Checksum(Checksum, Box<dyn Write + Send>),
+}
 
-        if self.next_thread_num != cur_thread {
-            return Err(())
+impl Output {
+    /// Write whatever is still to come, and flush the output.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Plain(mut out) => out.flush(),
+            Output::Gzip(encoder) => encoder.finish()?.flush(),
+            Output::Checksum(checksum, mut out) => {
+                writeln!(out, "{:016x}", checksum.finish())?;
+                out.flush()
+            }
         }
+    }
+}
 
-        self.next_thread_num+=1;
-        if self.next_thread_num == self.thread_count {
-            self.next_thread_num = 0;
+impl Write for Output {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match *self {
+            Output::Plain(ref mut out) => out.write(data),
+            Output::Gzip(ref mut encoder) => encoder.write(data),
+            Output::Checksum(ref mut checksum, _) => checksum.write(data),
         }
+    }
 
-        let to_gen = min(buf.len(), self.count);
-        for i in 0..to_gen {
-            self.last = (self.last * 3877 + 29573) % IM;
-            buf[i] = self.last;
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Output::Plain(ref mut out) => out.flush(),
+            Output::Gzip(ref mut encoder) => encoder.flush(),
+            Output::Checksum(..) => Ok(()),
         }
-        self.count -= to_gen;
-        Ok(to_gen)
     }
 }
 
-impl MyStdOut {
-    fn new(thread_count: u16) -> MyStdOut {
-        MyStdOut {
-            thread_count: thread_count,
-            next_thread_num: 0,
-            stdout: io::stdout()
+/// Where `BlockWriter` sends finished blocks.
+enum Sink {
+    /// The writer thread. The channel is bounded, so generating can only get
+    /// a little ahead of writing.
+    Thread(SyncSender<Vec<u8>>),
+    /// The output itself, for targets without threads such as wasm32-wasi,
+    /// with each block going back to the ring once it's written.
+    Direct(Output, SyncSender<Vec<u8>>),
+}
+
+/// Sends the output on a block at a time.
+struct BlockWriter {
+    sink: Sink,
+    /// The ring of buffers for blocks, as the writer finishes with them.
+    pool: Receiver<Vec<u8>>,
+    /// Output from `write` that isn't a block in itself.
+    buf: Vec<u8>,
+    /// Counts the blocks sent, for `--progress`.
+    #[cfg(feature = "progress")]
+    progress: Option<Progress>,
+}
+
+impl BlockWriter {
+    fn new(sink: Sink, pool: Receiver<Vec<u8>>) -> BlockWriter {
+        BlockWriter {
+            sink: sink,
+            pool: pool,
+            buf: Vec::with_capacity(BLKLEN + LINES),
+            #[cfg(feature = "progress")]
+            progress: None,
+        }
+    }
+
+    /// An empty buffer for another block from the ring, waiting for the writer
+    /// to hand one back if they're all in use.
+    fn buffer(&self) -> Vec<u8> {
+        // Unless the writer has stopped, in which case it has an error to
+        // report, and this block will never be sent.
+        let mut buf = self.pool.recv()
+            .unwrap_or_else(|_| Vec::with_capacity(BLKLEN + LINES));
+        buf.clear();
+        buf
+    }
+
+    /// Send a whole block, after anything written before it.
+    fn send(&mut self, block: Vec<u8>) -> io::Result<()> {
+        self.flush()?;
+        #[cfg(feature = "progress")]
+        {
+            if let Some(ref mut progress) = self.progress {
+                progress.add(block.len());
+            }
+        }
+        match self.sink {
+            Sink::Thread(ref tx) => tx.send(block).map_err(|_| {
+                io::Error::new(ErrorKind::BrokenPipe, "the writer thread has stopped")
+            }),
+            Sink::Direct(ref mut output, ref pool) => {
+                output.write_all(&block)?;
+                let _ = pool.send(block);
+                Ok(())
+            }
         }
     }
-    fn write(&mut self, data: &[u8], cur_thread: u16) -> io::Result<()> {
-        if self.next_thread_num != cur_thread {
-            return Err(io::Error::new(ErrorKind::Other, ""));
+
+    /// Send anything left over, and finish the output if it's written here.
+    fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        #[cfg(feature = "progress")]
+        {
+            if let Some(ref progress) = self.progress {
+                progress.finish();
+            }
+        }
+        match self.sink {
+            Sink::Thread(_) => Ok(()),
+            Sink::Direct(output, _) => output.finish(),
         }
+    }
+}
 
-        self.next_thread_num+=1;
-        if self.next_thread_num == self.thread_count {
-            self.next_thread_num = 0;
+impl Write for BlockWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= BLKLEN {
+            self.flush()?;
         }
+        Ok(data.len())
+    }
 
-        self.stdout.write_all(data)
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(())
+        }
+        let buf = self.buffer();
+        let block = std::mem::replace(&mut self.buf, buf);
+        self.send(block)
     }
 }
 
-fn make_random(data: &[(char, f32)]) -> Vec<(u32, u8)> {
+include!("common/progress.rs");
+
+include!("common/checksum.rs");
+
+/// The cumulative probabilities of `data` as thresholds for the generator's
+/// output. They add up in f64, like the reference implementations, since
+/// summing in f32 can leave the last threshold short of IM.
+fn make_random(data: &[(char, f64)]) -> Vec<(u32, u8)> {
     let mut acc = 0.;
     data.iter()
         .map(|&(ch, p)| {
             acc += p;
-            (MyRandom::normalize(acc), ch as u8)
+            (LcgRng::normalize(acc), ch as u8)
         })
         .collect()
 }
 
-fn make_fasta2<I: Iterator<Item=u8>>(header: &str, mut it: I, mut n: usize)
-    -> io::Result<()> {
-    let mut sysout = BufWriter::new(io::stdout());
-    try!(sysout.write_all(header.as_bytes()));
-    let mut line = [0u8; LINE_LENGTH + 1];
+/// Map every number the generator can produce straight to its base, instead
+/// of searching the table from `make_random` for each one. Numbers past the
+/// end of the table get the last base.
+fn make_lookup(data: &[(u32, u8)]) -> Vec<u8> {
+    let mut j = 0;
+    (0..IM).map(|rn| {
+        while j + 1 < data.len() && data[j].0 < rn {
+            j += 1;
+        }
+        data[j].1
+    }).collect()
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Write `n` bases repeating `seq` over and over. The lines repeat after
+/// lcm(seq.len(), LINE_LENGTH) bases, so that many are laid out once, newlines
+/// and all, and then copied as many times as it takes.
+fn make_repeat(header: &str, seq: &[u8], mut n: usize,
+               out: &mut BlockWriter) -> io::Result<()> {
+    out.write_all(header.as_bytes())?;
+    let period = seq.len() / gcd(seq.len(), LINE_LENGTH) * LINE_LENGTH;
+    let mut lines = Vec::with_capacity(period + period / LINE_LENGTH);
+    let bases: Vec<u8> = seq.iter().cycle().take(period).cloned().collect();
+    for line in bases.chunks(LINE_LENGTH) {
+        lines.extend_from_slice(line);
+        lines.push(b'\n');
+    }
     while n > 0 {
-        let nb = min(LINE_LENGTH, n);
-        for i in 0..nb {
-            line[i] = it.next().unwrap();
+        let mut block = out.buffer();
+        while n > 0 && block.len() < BLKLEN {
+            // A whole period, unless this is the end of the sequence.
+            let count = min(period, n);
+            block.extend_from_slice(&lines[..count + count / LINE_LENGTH]);
+            if count % LINE_LENGTH != 0 {
+                block.push(b'\n');
+            }
+            n -= count;
         }
-        n -= nb;
-        line[nb] = '\n' as u8;
-        try!(sysout.write_all(&line[..(nb+1)]));
+        out.send(block)?;
     }
     Ok(())
 }
 
-fn do_fasta(thread_num: u16, rng: Arc<Mutex<MyRandom>>,
-            wr: Arc<Mutex<MyStdOut>>, data: Vec<(u32, u8)>) {
-    let mut rng_buf = [0u32; BLKLEN];
-    let mut out_buf = [0u8; BLKLEN + LINES];
-    let mut count;
-    loop {
-        loop {
-            if let Ok(x) = rng.lock().unwrap().gen(&mut rng_buf, thread_num) {
-                count = x;
-                break;
-            }
-        };
+/// Fill `out_buf` with `count` random bases starting from `rng`, with a
+/// newline after each line, and return how many bytes that took. If `reverse`,
+/// the bases are written from the end of `out_buf` back to the start.
+fn do_fasta(rng: LcgRng, count: usize, out_buf: &mut [u8], lookup: &[u8],
+            reverse: bool) -> usize {
+    let bases = &mut out_buf[..count];
+    fill_random(rng, bases, lookup);
+    if reverse {
+        bases.reverse();
+    }
+    // Spread the bases out into lines, starting from the end so that none
+    // are overwritten before they're moved.
+    let lines = (count + LINE_LENGTH - 1) / LINE_LENGTH;
+    for i in (0..lines).rev() {
+        let start = i * LINE_LENGTH;
+        let end = min(start + LINE_LENGTH, count);
+        let dest = start + i;
+        out_buf.copy_within(start..end, dest);
+        out_buf[dest + end - start] = b'\n';
+    }
+    count + lines
+}
+
+/// Run `f` on each of `blocks` and its index, in parallel. rayon shares them
+/// out between the threads of the pool `in_pool` set up.
+#[cfg(not(feature = "std-threads"))]
+fn for_each_block<F>(blocks: &mut [Vec<u8>], _threads: usize, f: F)
+    where F: Fn(usize, &mut Vec<u8>) + Sync
+{
+    blocks.par_iter_mut().enumerate().for_each(|(i, buf)| f(i, buf));
+}
 
-        if count == 0 {
-            break;
+/// `for_each_block` without rayon, splitting the blocks evenly between
+/// `threads` scoped threads. wasi can't start any threads, so there they are
+/// all done in turn.
+#[cfg(feature = "std-threads")]
+fn for_each_block<F>(blocks: &mut [Vec<u8>], threads: usize, f: F)
+    where F: Fn(usize, &mut Vec<u8>) + Sync
+{
+    let threads = if cfg!(target_os = "wasi") { 1 } else { threads };
+    let per_thread = std::cmp::max(1, (blocks.len() + threads - 1) / threads);
+    if threads == 1 || blocks.len() == 1 {
+        for (i, buf) in blocks.iter_mut().enumerate() {
+            f(i, buf);
         }
-        let mut line_count = 0;
-        for i in 0..count {
-            if i % LINE_LENGTH == 0 && i > 0 {
-                out_buf[i+line_count] = b'\n';
-                line_count += 1;
-            }
-            let rn = rng_buf[i];
-            for j in &data {
-                if j.0 >= rn {
-                    out_buf[i+line_count] = j.1;
-                    break;
+        return;
+    }
+    let f = &f;
+    thread::scope(|s| {
+        for (t, chunk) in blocks.chunks_mut(per_thread).enumerate() {
+            s.spawn(move || {
+                for (j, buf) in chunk.iter_mut().enumerate() {
+                    f(t * per_thread + j, buf);
                 }
+            });
+        }
+    });
+}
+
+/// Write `n` random bases, or their reverse complement, generating a batch of
+/// blocks at a time in parallel on `threads` threads. Each block jumps ahead
+/// to its own place in the random sequence, so the output is the same as
+/// generating them one after another, however many threads there are.
+fn make_fasta(header: &str, rng: &mut LcgRng, data: &[(u32, u8)], n: usize,
+              reverse: bool, threads: usize, out: &mut BlockWriter) -> io::Result<()> {
+    out.write_all(header.as_bytes())?;
+    let mut lookup = make_lookup(data);
+    if reverse {
+        for b in &mut lookup {
+            *b = complement(*b);
+        }
+    }
+    let batch_len = BLKLEN * BLOCKS_PER_THREAD * threads;
+    let start = *rng;
+    let mut blocks = vec![];
+    let mut done = 0;
+    while done < n {
+        let count = min(batch_len, n - done);
+        let num_blocks = (count + BLKLEN - 1) / BLKLEN;
+        for _ in 0..num_blocks {
+            let mut buf = out.buffer();
+            buf.resize(BLKLEN + LINES, 0);
+            blocks.push(buf);
+        }
+        for_each_block(&mut blocks, threads, |i, buf| {
+            // The block's place in the output, and where its bases come
+            // from in the random sequence.
+            let first = done + i * BLKLEN;
+            let count = min(BLKLEN, n - first);
+            let mut rng = start;
+            rng.advance(if reverse { n - first - count } else { first });
+            let len = do_fasta(rng, count, buf, &lookup, reverse);
+            buf.truncate(len);
+        });
+        for block in blocks.drain(..) {
+            out.send(block)?;
+        }
+        done += count;
+    }
+    rng.advance(n);
+    Ok(())
+}
+
+/// Write `count` short records of `len` bases, taking the generators of
+/// `records` in turn. Each read carries on from where the last one from the
+/// same generator stopped, and the random ones all share `rng`.
+fn make_reads(records: &[Record], count: usize, len: usize, rng: &mut LcgRng,
+              reverse: bool, out: &mut BlockWriter) -> io::Result<()> {
+    let lookups: Vec<Vec<u8>> = records.iter().map(|record| match record.generator {
+        Generator::Random(ref table) => make_lookup(table),
+        Generator::Repeat(_) => vec![],
+    }).collect();
+    let mut positions = vec![0; records.len()];
+    let mut bases = Vec::with_capacity(len);
+    for (i, j) in (0..records.len()).cycle().take(count).enumerate() {
+        let record = &records[j];
+        bases.clear();
+        match record.generator {
+            Generator::Repeat(ref seq) => {
+                bases.extend(seq.iter().cycle().skip(positions[j]).take(len));
+                positions[j] = (positions[j] + len) % seq.len();
+            }
+            Generator::Random(_) => {
+                let lookup = &lookups[j];
+                bases.extend(rng.take(len).map(|x| lookup[x as usize]));
             }
         }
-        out_buf[count+line_count] = b'\n';
+        if reverse {
+            bases.reverse();
+            for b in &mut bases {
+                *b = complement(*b);
+            }
+        }
+        // The record's own header, less its '>', describes the read.
+        write!(out, ">READ_{} {}", i + 1, &record.header[1..])?;
+        for line in bases.chunks(LINE_LENGTH) {
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+include!("common/iupac.rs");
+
+/// The complement of a base, as `reverse_complement` prints it.
+fn complement(b: u8) -> u8 {
+    IUPAC_TABLE[b as usize]
+}
+
+/// How the bases of a record are generated.
+enum Generator {
+    /// Repeat a sequence over and over.
+    Repeat(Vec<u8>),
+    /// Pick each base at random, using a table from `make_random`.
+    Random(Vec<(u32, u8)>),
+}
+
+struct Record {
+    /// The header line, with its '>' and newline.
+    header: String,
+    generator: Generator,
+    len: usize,
+}
 
-        while let Err(_) = wr.lock()
-                .unwrap()
-                .write(&out_buf[..(count+line_count+1)], thread_num) {};
+impl Record {
+    /// Size of the output for this record.
+    fn output_len(&self) -> u64 {
+        let n = self.len;
+        (self.header.len() + n + (n + LINE_LENGTH - 1) / LINE_LENGTH) as u64
     }
 }
 
-fn make_fasta(header: &str, rng: Arc<Mutex<MyRandom>>,
-                 data: Vec<(u32, u8)>, num_threads: u16
-             ) -> io::Result<()> {
+/// Read the records to generate from a TOML file with a `[[record]]` table for
+/// each one, like
+///
+/// ```toml
+/// [[record]]
+/// header = "ONE Homo sapiens alu"
+/// type = "repeat"
+/// sequence = "GGCCGGGCGCGGTGGCTCACGCCTGTAATCCCAGCACTTT"
+/// length = 2000
+///
+/// [[record]]
+/// header = "TWO IUB ambiguity codes"
+/// type = "random"
+/// bases = "acgt"
+/// probabilities = [0.27, 0.23, 0.23, 0.27]
+/// length = 3000
+/// ```
+///
+/// The random records all draw from the same generator, one after another.
+fn read_spec(path: &Path) -> Result<Vec<Record>, String> {
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| e.to_string())?;
+    let spec: toml::Value = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+    let records = spec.get("record").and_then(|r| r.as_array())
+        .ok_or("no [[record]] tables".to_string())?;
+    records.iter().enumerate().map(|(i, record)| {
+        let field = |name: &str| {
+            record.get(name).ok_or(format!("record {}: no {}", i + 1, name))
+        };
+        let string = |name: &str| {
+            field(name).and_then(|value| {
+                value.as_str().ok_or(format!("record {}: {} isn't a string", i + 1, name))
+            })
+        };
+        let header = string("header")?;
+        let len = field("length")?.as_integer().filter(|&n| n >= 0)
+            .ok_or(format!("record {}: invalid length", i + 1))?;
+        let generator = match string("type")? {
+            "repeat" => {
+                let seq = string("sequence")?;
+                if seq.is_empty() {
+                    return Err(format!("record {}: empty sequence", i + 1));
+                }
+                Generator::Repeat(seq.as_bytes().to_vec())
+            }
+            "random" => {
+                let bases = string("bases")?;
+                let probabilities = field("probabilities")?.as_array()
+                    .and_then(|a| a.iter().map(|p| p.as_float()).collect::<Option<Vec<_>>>())
+                    .ok_or(format!("record {}: probabilities aren't a list of numbers",
+                                   i + 1))?;
+                if bases.is_empty() || !bases.is_ascii() ||
+                   bases.len() != probabilities.len() {
+                    return Err(format!("record {}: need one probability for each of the \
+                                        ASCII bases", i + 1));
+                }
+                let data: Vec<(char, f64)> = bases.chars()
+                    .zip(probabilities.iter().cloned())
+                    .collect();
+                let mut table = make_random(&data);
+                // The last base takes whatever is left, so every random number
+                // has one even if the probabilities don't quite add up to 1.
+                table.last_mut().unwrap().0 = IM;
+                Generator::Random(table)
+            }
+            other => return Err(format!("record {}: unknown type {:?}", i + 1, other)),
+        };
+        Ok(Record {
+            header: format!(">{}\n", header),
+            generator: generator,
+            len: len as usize,
+        })
+    }).collect()
+}
 
-    let stdout = Arc::new(Mutex::new(MyStdOut::new(num_threads)));
-    try!(io::stdout().write_all(header.as_bytes()));
-    let mut threads = Vec::new();
-    for thread in 0..num_threads {
-        let d = data.clone();
-        let rng_clone = rng.clone();
-        let stdout_clone = stdout.clone();
-        threads.push(thread::spawn(move || {
-            do_fasta(thread, rng_clone, stdout_clone, d);
-        }));
+/// The 20 amino acids, with roughly how often each is found in proteins.
+/// `--protein` scales these so they add up to 1.
+const AMINO_ACIDS: &[(char, f64)] = &[
+    ('A', 0.0825), ('R', 0.0553), ('N', 0.0406), ('D', 0.0545), ('C', 0.0137),
+    ('Q', 0.0393), ('E', 0.0675), ('G', 0.0707), ('H', 0.0227), ('I', 0.0596),
+    ('L', 0.0966), ('K', 0.0584), ('M', 0.0242), ('F', 0.0386), ('P', 0.0470),
+    ('S', 0.0656), ('T', 0.0534), ('W', 0.0108), ('Y', 0.0292), ('V', 0.0687),
+];
+
+/// Parse a list of weights like `W=0.02,C=0.05`.
+fn parse_frequencies(list: &str) -> Option<Vec<(char, f64)>> {
+    list.split(',').map(|item| {
+        let mut parts = item.splitn(2, '=');
+        let name = parts.next()?;
+        let weight = parts.next()?.parse().ok().filter(|&p: &f64| p >= 0.)?;
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) => Some((ch.to_ascii_uppercase(), weight)),
+            _ => None,
+        }
+    }).collect()
+}
+
+/// Build the table for random amino acids, with the weights from
+/// `--frequencies` in place of the usual ones, scaled to add up to 1.
+fn make_protein(frequencies: &[(char, f64)]) -> io::Result<Vec<(u32, u8)>> {
+    let mut weights = AMINO_ACIDS.to_vec();
+    for &(ch, p) in frequencies {
+        match weights.iter_mut().find(|w| w.0 == ch) {
+            Some(w) => w.1 = p,
+            None => {
+                return Err(invalid_input(format!("--frequencies: {:?} isn't an amino acid", ch)));
+            }
+        }
     }
-    for thread_guard in threads {
-        thread_guard.join().unwrap();
+    let total: f64 = weights.iter().map(|w| w.1).sum();
+    if !(total > 0.) {
+        return Err(invalid_input("--frequencies: the weights add up to nothing".into()));
     }
-    Ok(())
+    for w in &mut weights {
+        w.1 /= total;
+    }
+    let mut table = make_random(&weights);
+    table.last_mut().unwrap().0 = IM;
+    Ok(table)
 }
 
-fn main() {
-    let n = std::env::args_os().nth(1)
-        .and_then(|s| s.into_string().ok())
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(1000);
+/// Run `op` on a rayon pool of `threads` threads, or on rayon's default one,
+/// with a thread for each CPU, if no number was asked for.
+#[cfg(not(feature = "std-threads"))]
+fn in_pool<F, R>(threads: Option<usize>, op: F) -> R
+    where F: FnOnce() -> R + Send, R: Send
+{
+    match threads {
+        Some(n) => ThreadPool::new(Configuration::new().set_num_threads(n)).unwrap().install(op),
+        None => op(),
+    }
+}
+
+/// Without rayon, `for_each_block` starts its own threads.
+#[cfg(feature = "std-threads")]
+fn in_pool<F, R>(_threads: Option<usize>, op: F) -> R
+    where F: FnOnce() -> R
+{
+    op()
+}
+
+/// The kinds of record `--record` can ask for: the ALU repeat, the two random
+/// DNA alphabets, and random amino acids.
+const RECORD_KINDS: &[&str] = &["repeat", "iub", "homosapiens", "protein"];
+
+/// Parse a record like `iub:5000`.
+fn parse_record(record: &str) -> Option<(String, usize)> {
+    let mut parts = record.splitn(2, ':');
+    let kind = parts.next().filter(|kind| RECORD_KINDS.contains(kind))?;
+    let len = parts.next()?.parse().ok()?;
+    Some((kind.to_string(), len))
+}
+
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: fasta [OPTIONS] [N]
+
+Print the benchmark's three DNA records, 2N, 3N and 5N bases long (N is 1000
+unless given), the first repeating a sequence and the others at random.
 
-    let num_threads: u16 = num_cpus::get() as u16;
+  --seed SEED           seed the random generator with SEED rather than 42
+  -o, --output FILE     write to FILE rather than stdout
+  --gzip                compress the output with gzip
+  --checksum            print a hash of the output rather than the output
+  --progress            show how much has been generated so far on stderr
+                        (needs the progress feature)
+  --reverse-complement  print the reverse complement of each sequence
+  --record KIND:LEN     print a record of LEN bases of KIND in place of the
+                        usual three, where KIND is repeat, iub, homosapiens or
+                        protein; can be given more than once
+  --protein             print one record of 10N random amino acids instead
+  --frequencies LIST    weights of some of the amino acids, like W=0.02,C=0.05
+  --spec FILE           print the records described in a TOML file instead
+  --reads COUNT         print COUNT short records, taking the generators in
+                        turn, rather than one long one from each
+  --read-length LEN     make each of those LEN bases long rather than 150
+  --threads N           generate on N threads rather than one for each CPU
+  -h, --help            print this message
+  --version             print how this was built
+";
+
+include!("common/main.rs");
+
+include!("common/path_error.rs");
+
+fn run() -> io::Result<()> {
+    let mut n: usize = 1000;
+    // The benchmark's output uses the seed 42; others give different data.
+    let mut seed = 42;
+    let mut output = None;
+    let mut spec = None;
+    let mut reverse = false;
+    let mut gzip = false;
+    let mut checksum = false;
+    let mut progress = false;
+    let mut protein = false;
+    let mut frequencies = vec![];
+    let mut reads = None;
+    let mut scenario = vec![];
+    let mut read_len = None;
+    // RAYON_NUM_THREADS sets the default, as for reverse_complement.
+    let mut threads = std::env::var("RAYON_NUM_THREADS").ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0);
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--seed") => {
+                // Anything from IM up would overflow the generator.
+                seed = args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&s| s < IM)
+                    .ok_or_else(|| {
+                        invalid_input("--seed needs a number less than 139968".into())
+                    })?;
+            }
+            Some("-o") | Some("--output") => {
+                let file = args.next()
+                    .ok_or_else(|| invalid_input("--output needs a file name".into()))?;
+                output = Some(PathBuf::from(file));
+            }
+            // Print the reverse complement of each sequence instead, like
+            // piping the output through reverse_complement.
+            Some("--reverse-complement") => reverse = true,
+            Some("--gzip") => gzip = true,
+            // Print a hash of the output instead of the output itself.
+            Some("--checksum") => checksum = true,
+            Some("--progress") => progress = true,
+            // One record of random amino acids instead of the three DNA ones.
+            Some("--protein") => protein = true,
+            // Weights for some of the amino acids, like `W=0.02,C=0.05`.
+            Some("--frequencies") => {
+                let list = args.next();
+                frequencies = list.as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(parse_frequencies)
+                    .ok_or_else(|| {
+                        invalid_input("--frequencies needs a list like W=0.02,C=0.05".into())
+                    })?;
+            }
+            // The spec gives the length of every record, so `n` is unused.
+            Some("--spec") => {
+                let file = args.next()
+                    .ok_or_else(|| invalid_input("--spec needs a file name".into()))?;
+                spec = Some(PathBuf::from(file));
+            }
+            // Records to generate in place of the usual three, like
+            // `repeat:1000`, each of one kind from RECORD_KINDS.
+            Some("--record") => {
+                let record = args.next();
+                scenario.push(record.as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(parse_record)
+                    .ok_or_else(|| {
+                        invalid_input("--record needs a kind and a length, like repeat:1000".into())
+                    })?);
+            }
+            // Many short records from the same generators, instead of one
+            // long one from each.
+            Some("--reads") => {
+                reads = Some(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| invalid_input("--reads needs a number of records".into()))?);
+            }
+            Some("--read-length") => {
+                read_len = Some(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| {
+                        invalid_input("--read-length needs a number of bases".into())
+                    })?);
+            }
+            // Generate on this many threads rather than one for each CPU.
+            Some("--threads") => {
+                threads = Some(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid_input("--threads needs a positive number".into()))?);
+            }
+            Some("-h") | Some("--help") => return io::stdout().write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut io::stdout()),
+            _ => n = arg.to_str().and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_input(format!("unknown argument {:?}; see --help", arg)))?,
+        }
+    }
 
-    let rng = Arc::new(Mutex::new(MyRandom::new(n*3, num_threads)));
     let alu: &[u8] = b"GGCCGGGCGCGGTGGCTCACGCCTGTAATCCCAGCACTTT\
                        GGGAGGCCGAGGCGGGCGGATCACCTGAGGTCAGGAGTTC\
                        GAGACCAGCCTGGCCAACATGGTGAAACCCCGTCTCTACT\
@@ -205,15 +916,240 @@ fn main() {
                         ('g', 0.1975473066391),
                         ('t', 0.3015094502008)];
 
-    make_fasta2(">ONE Homo sapiens alu\n",
-                    alu.iter().cycle().map(|c| *c), n * 2).unwrap();
-    make_fasta(">TWO IUB ambiguity codes\n",
-                    rng.clone(), make_random(iub), num_threads).unwrap();
+    if (protein || spec.is_some()) && !scenario.is_empty() {
+        return Err(invalid_input("--record can't be used with --spec or --protein".into()));
+    }
+    if protein && spec.is_some() {
+        return Err(invalid_input("--protein can't be used with --spec".into()));
+    }
+    let any_protein = protein || scenario.iter().any(|r| r.0 == "protein");
+    if any_protein && reverse {
+        return Err(invalid_input("proteins don't have a reverse complement".into()));
+    }
+    if !any_protein && !frequencies.is_empty() {
+        return Err(invalid_input("--frequencies only applies to protein records".into()));
+    }
+    if checksum && (output.is_some() || gzip) {
+        return Err(invalid_input("--checksum can't be used with -o or --gzip".into()));
+    }
+    if reads.is_none() && read_len.is_some() {
+        return Err(invalid_input("--read-length only applies to --reads".into()));
+    }
+    if progress && !cfg!(feature = "progress") {
+        return Err(invalid_input("--progress needs the progress feature".into()));
+    }
+
+    // Checked here, whether or not it's used, so the records can't fail.
+    let protein_table = make_protein(&frequencies)?;
+    // Each record is a multiple of N long, which mustn't wrap round.
+    let times = |k: usize| n.checked_mul(k).ok_or_else(|| invalid_input("N is too large".into()));
+    let records = match spec {
+        Some(path) => read_spec(&path)
+            .map_err(|e| invalid_input(format!("{}: {}", path.display(), e)))?,
+        // As long as the three DNA records put together.
+        None if protein => vec![Record {
+            header: ">PROTEIN random amino acids\n".to_string(),
+            generator: Generator::Random(protein_table),
+            len: times(10)?,
+        }],
+        None if !scenario.is_empty() => {
+            scenario.iter().enumerate().map(|(i, &(ref kind, len))| {
+                let (generator, description) = match &kind[..] {
+                    "repeat" => (Generator::Repeat(alu.to_vec()), "Homo sapiens alu"),
+                    "iub" => (Generator::Random(make_random(iub)), "IUB ambiguity codes"),
+                    "homosapiens" => {
+                        (Generator::Random(make_random(homosapiens)), "Homo sapiens frequency")
+                    }
+                    _ => (Generator::Random(protein_table.clone()), "random amino acids"),
+                };
+                Record {
+                    header: format!(">RECORD_{} {}\n", i + 1, description),
+                    generator: generator,
+                    len: len,
+                }
+            }).collect()
+        }
+        None => vec![
+            Record {
+                header: ">ONE Homo sapiens alu\n".to_string(),
+                generator: Generator::Repeat(alu.to_vec()),
+                len: times(2)?,
+            },
+            Record {
+                header: ">TWO IUB ambiguity codes\n".to_string(),
+                generator: Generator::Random(make_random(iub)),
+                len: times(3)?,
+            },
+            Record {
+                header: ">THREE Homo sapiens frequency\n".to_string(),
+                generator: Generator::Random(make_random(homosapiens)),
+                len: times(5)?,
+            },
+        ],
+    };
+
+    let out: Box<dyn Write + Send> = match output {
+        // Writing to a file of the right size through a big buffer is much
+        // quicker than writing to a pipe.
+        Some(path) => {
+            let file = File::create(&path).map_err(|e| path_error(&path, e))?;
+            // The size of the reads isn't worth working out, and devices and
+            // pipes such as /dev/null have no size to set.
+            if !gzip && reads.is_none() && file.metadata()?.is_file() {
+                file.set_len(records.iter().map(|r| r.output_len()).sum())?;
+            }
+            Box::new(BufWriter::with_capacity(4 << 20, file))
+        }
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    let output = if checksum {
+        Output::Checksum(Checksum::new(), out)
+    } else if gzip {
+        Output::Gzip(GzEncoder::new(out, flate2::Compression::default()))
+    } else {
+        Output::Plain(out)
+    };
+
+    // Write on a separate thread, with room for about one batch of blocks to
+    // be generated while the one before is written, or where there are no
+    // threads, write each block as soon as it's made. The blocks go round a
+    // fixed ring of buffers, enough for two batches, the block being written
+    // into and the one that replaces it when it's sent, so nothing more is
+    // allocated and generating waits for writing to catch up.
+    let thread_count = threads.unwrap_or_else(num_cpus::get);
+    let ring_len = 2 * BLOCKS_PER_THREAD * thread_count + 2;
+    let (pool_tx, pool) = sync_channel(ring_len);
+    for _ in 0..ring_len {
+        pool_tx.send(Vec::with_capacity(BLKLEN + LINES)).unwrap();
+    }
+    let (sink, writer) = if cfg!(target_os = "wasi") {
+        (Sink::Direct(output, pool_tx), None)
+    } else {
+        let (tx, rx) = sync_channel::<Vec<u8>>(BLOCKS_PER_THREAD * thread_count);
+        let writer = thread::spawn(move || -> io::Result<()> {
+            let mut output = output;
+            for block in rx {
+                output.write_all(&block)?;
+                // The generator may have finished and not need it.
+                let _ = pool_tx.send(block);
+            }
+            output.finish()
+        });
+        (Sink::Thread(tx), Some(writer))
+    };
+
+    let mut blocks = BlockWriter::new(sink, pool);
+    #[cfg(feature = "progress")]
+    {
+        if progress {
+            // The size of the reads isn't worth working out here either.
+            let total = if reads.is_none() {
+                records.iter().map(|r| r.output_len()).sum()
+            } else {
+                0
+            };
+            blocks.progress = Some(Progress::new(total));
+        }
+    }
+    let mut rng = LcgRng::new(seed);
+    let result = in_pool(threads, || if let Some(count) = reads {
+        make_reads(&records, count, read_len.unwrap_or(DEFAULT_READ_LEN), &mut rng, reverse,
+                   &mut blocks)
+    } else {
+        records.iter().map(|record| {
+            match record.generator {
+                Generator::Repeat(ref seq) if reverse => {
+                    // Go backwards round the cycle from where the sequence ends.
+                    let last = (record.len + seq.len() - 1) % seq.len();
+                    let rc: Vec<u8> = seq.iter().rev().cycle().skip(seq.len() - 1 - last)
+                        .take(seq.len())
+                        .map(|&c| complement(c))
+                        .collect();
+                    make_repeat(&record.header, &rc, record.len, &mut blocks)
+                }
+                Generator::Repeat(ref seq) => {
+                    make_repeat(&record.header, seq, record.len, &mut blocks)
+                }
+                Generator::Random(ref table) => {
+                    make_fasta(&record.header, &mut rng, table, record.len, reverse,
+                               thread_count, &mut blocks)
+                }
+            }
+        }).collect::<io::Result<()>>()
+    }.and_then(|_| blocks.finish()));
+
+    // If the writer failed, that's the error to report.
+    match writer {
+        Some(writer) => writer.join().unwrap().and(result),
+        None => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Fill = fn(LcgRng, &mut [u8], &[u8]);
+
+    /// Each version of `fill_random` this CPU can run.
+    fn kernels() -> Vec<(&'static str, Fill)> {
+        let mut kernels: Vec<(&'static str, Fill)> = vec![("scalar", fill_random_scalar)];
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("sse2") {
+                kernels.push(("sse2", |rng, out, lookup| unsafe {
+                    fill_random_sse2(rng, out, lookup)
+                }));
+            }
+            if is_x86_feature_detected!("avx") {
+                kernels.push(("avx", |rng, out, lookup| unsafe {
+                    fill_random_avx(rng, out, lookup)
+                }));
+            }
+        }
+        kernels
+    }
 
-    rng.lock().unwrap().reset(n*5);
+    /// Look every number up by its low or its high bits, so between them the
+    /// output of `fill_random` gives the whole of each number.
+    fn lookups() -> [Vec<u8>; 2] {
+        [(0..IM).map(|x| x as u8).collect(), (0..IM).map(|x| (x >> 8) as u8).collect()]
+    }
 
-    make_fasta(">THREE Homo sapiens frequency\n",
-                    rng, make_random(homosapiens), num_threads).unwrap();
+    #[test]
+    fn lanes_match_one_generator() {
+        for lookup in &lookups() {
+            for &seed in &[0, 1, 42, 4095, IM - 1] {
+                for len in (0..40).chain(vec![1000, 1003, BLKLEN + 5]) {
+                    let rng = LcgRng::new(seed);
+                    let expected: Vec<u8> =
+                        rng.take(len).map(|x| lookup[x as usize]).collect();
+                    for &(name, fill) in &kernels() {
+                        let mut out = vec![0; len];
+                        fill(rng, &mut out, lookup);
+                        assert_eq!(out, expected, "{}, seed {}, length {}", name, seed, len);
+                    }
+                }
+            }
+        }
+    }
 
-    io::stdout().flush().unwrap();
+    /// Every state the generator can be in, stepped LANES at a time, as the
+    /// vector lanes do it and as the scalar ones do.
+    #[test]
+    fn lanes_step_every_state() {
+        for lookup in &lookups() {
+            let mut scalar = vec![0; LANES * 2];
+            let mut vector = vec![0; LANES * 2];
+            let kernels = kernels();
+            for seed in 0..IM {
+                fill_random_scalar(LcgRng::new(seed), &mut scalar, lookup);
+                for &(name, fill) in &kernels[1..] {
+                    fill(LcgRng::new(seed), &mut vector, lookup);
+                    assert_eq!(vector, scalar, "{}, seed {}", name, seed);
+                }
+            }
+        }
+    }
 }
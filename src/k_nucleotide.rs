@@ -7,8 +7,13 @@
 
 extern crate futures;
 extern crate futures_cpupool;
+extern crate num_cpus;
 extern crate ordermap;
 
+use std::cmp::min;
+use std::mem;
+use std::io::{self, IsTerminal, Write};
+use std::ops::Range;
 use std::sync::Arc;
 use std::hash::{Hasher, BuildHasherDefault};
 use futures::Future;
@@ -16,6 +21,9 @@ use futures_cpupool::CpuPool;
 use Item::*;
 use ordermap::OrderMap;
 
+include!("common/alloc.rs");
+include!("common/packing.rs");
+
 struct NaiveHasher(u64);
 impl Default for NaiveHasher {
     fn default() -> Self {
@@ -37,112 +45,136 @@ type NaiveBuildHasher = BuildHasherDefault<NaiveHasher>;
 type NaiveHashMap<K, V> = OrderMap<K, V, NaiveBuildHasher>;
 type Map = NaiveHashMap<Code, u32>;
 
-#[derive(Hash, PartialEq, PartialOrd, Ord, Eq, Clone, Copy)]
-struct Code(u64);
-impl Code {
-    fn push(&mut self, c: u8, mask: u64) {
-        self.0 <<= 2;
-        self.0 |= c as u64;
-        self.0 &= mask;
-    }
-    fn from_str(s: &str) -> Code {
-        let mask = Code::make_mask(s.len());
-        let mut res = Code(0);
-        for c in s.as_bytes() {
-            res.push(Code::encode(*c), mask);
-        }
-        res
-    }
-    fn to_string(&self, frame: usize) -> String {
-        let mut res = vec![];
-        let mut code = self.0;
-        for _ in 0..frame {
-            let c = match code as u8 & 0b11 {
-                c if c == Code::encode(b'A') => b'A',
-                c if c == Code::encode(b'T') => b'T',
-                c if c == Code::encode(b'G') => b'G',
-                c if c == Code::encode(b'C') => b'C',
-                _ => unreachable!(),
-            };
-            res.push(c);
-            code >>= 2;
+/// Number of leading bases that pick a k-mer's shard, when the input is
+/// counted in parts. With only one part there's nothing to merge, and one
+/// big map is quicker than many small ones.
+const SHARD_BASES: usize = 2;
+
+/// Counts of k-mers, split into shards by their first few bases, so that
+/// the shards from counting different parts of the input can be merged in
+/// parallel.
+struct Counts {
+    shards: Vec<Map>,
+    shift: usize,
+}
+impl Counts {
+    fn new(frame: usize, shard_bases: usize) -> Counts {
+        let bases = min(frame, shard_bases);
+        Counts {
+            shards: (0..1 << (2 * bases)).map(|_| Map::default()).collect(),
+            shift: 2 * (frame - bases),
         }
-        res.reverse();
-        String::from_utf8(res).unwrap()
     }
-    fn make_mask(frame: usize) -> u64 {
-        (1u64 << (2 * frame)) - 1
+    fn shard(&self, code: Code) -> usize {
+        // With one shard, the shift can be the whole 64 bits of a 32-mer.
+        code.0.checked_shr(self.shift as u32).unwrap_or(0) as usize
+    }
+    fn get(&self, code: Code) -> u32 {
+        self.shards[self.shard(code)].get(&code).cloned().unwrap_or(0)
     }
-    fn encode(c: u8) -> u8 {
-        (c & 0b110) >> 1
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a Code, &'a u32)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
     }
 }
 
-struct Iter<'a> {
-    iter: std::slice::Iter<'a, u8>,
-    code: Code,
-    mask: u64,
-}
-impl<'a> Iter<'a> {
-    fn new(input: &[u8], frame: usize) -> Iter {
-        let mut iter = input.iter();
-        let mut code = Code(0);
-        let mask = Code::make_mask(frame);
-        for c in iter.by_ref().take(frame - 1) {
-            code.push(*c, mask);
+fn gen_freq(input: &[u8], frame: usize, shard_bases: usize) -> Counts {
+    let mut freq = Counts::new(frame, shard_bases);
+    let mut count = |bases: &[u8]| {
+        if freq.shards.len() == 1 {
+            let map = &mut freq.shards[0];
+            for code in Iter::new(bases, frame) {
+                *map.entry(code).or_insert(0) += 1;
+            }
+        } else {
+            for code in Iter::new(bases, frame) {
+                let shard = freq.shard(code);
+                *freq.shards[shard].entry(code).or_insert(0) += 1;
+            }
         }
-        Iter {
-            iter: iter,
-            code: code,
-            mask: mask,
+    };
+    // K-mers that take in something other than a base aren't counted.
+    if input.contains(&Code::OTHER) {
+        for bases in input.split(|&c| c == Code::OTHER) {
+            count(bases);
         }
+    } else {
+        count(input);
     }
+    freq
 }
-impl<'a> Iterator for Iter<'a> {
-    type Item = Code;
-    fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|&c| {
-            self.code.push(c, self.mask);
-            self.code
-        })
+
+/// Split the input into `n` parts to count separately. Each holds the
+/// k-mers starting in one range of positions, so it runs on `frame - 1`
+/// bytes into the next, and every k-mer is counted exactly once.
+fn chunks(len: usize, frame: usize, n: usize) -> Vec<Range<usize>> {
+    let starts = (len + 1).saturating_sub(frame);
+    let size = (starts + n - 1) / n;
+    let mut chunks: Vec<_> = (0..n)
+        .map(|i| min(i * size, starts)..min((i + 1) * size, starts))
+        .filter(|range| !range.is_empty())
+        .map(|range| range.start..range.end + frame - 1)
+        .collect();
+    if chunks.is_empty() {
+        chunks.push(0..len);
     }
+    chunks
 }
 
-fn gen_freq(input: &[u8], frame: usize) -> Map {
-    let mut freq = Map::default();
-    for code in Iter::new(input, frame) {
-        *freq.entry(code).or_insert(0) += 1;
+/// Add up the counts from each part of the input, a shard at a time.
+fn merge(mut parts: Vec<Counts>, pool: &CpuPool) -> Counts {
+    let mut total = parts.pop().unwrap();
+    if parts.is_empty() {
+        return total;
     }
-    freq
+    let shards: Vec<_> = total.shards.drain(..).enumerate().map(|(i, mut shard)| {
+        let others: Vec<Map> = parts.iter_mut()
+            .map(|part| mem::replace(&mut part.shards[i], Map::default()))
+            .collect();
+        pool.spawn_fn(move || {
+            for other in others {
+                for (code, count) in other {
+                    *shard.entry(code).or_insert(0) += count;
+                }
+            }
+            Ok::<_, ()>(shard)
+        })
+    }).collect();
+    total.shards = shards.into_iter().map(|shard| shard.wait().unwrap()).collect();
+    total
 }
 
 #[derive(Clone, Copy)]
 enum Item {
     Freq(usize),
     Occ(&'static str),
+    /// Like `Freq`, but only the given number of the most frequent.
+    Top(usize, usize),
 }
 impl Item {
-    fn print(&self, freq: &Map) {
+    fn print(&self, freq: &Counts, out: &mut dyn Write) -> io::Result<()> {
         match *self {
-            Freq(frame) => {
-                let mut v: Vec<_> = freq.iter().map(|(&code, &count)| (count, code)).collect();
-                v.sort();
-                let total = v.iter().map(|&(count, _)| count).sum::<u32>() as f32;
-                for &(count, key) in v.iter().rev() {
-                    println!("{} {:.3}", key.to_string(frame), (count as f32 * 100.) / total);
-                }
-                println!("");
-            }
-            Occ(occ) => println!("{}\t{}", freq[&Code::from_str(occ)], occ),
+            Freq(frame) => print_top(freq, frame, usize::max_value(), out),
+            Occ(occ) => writeln!(out, "{}\t{}", freq.get(Code::from_str(occ)), occ),
+            Top(frame, n) => print_top(freq, frame, n, out),
         }
     }
-    fn gen_freq(&self, input: &[u8]) -> Map {
+    fn frame(&self) -> usize {
         match *self {
-            Freq(frame) => gen_freq(input, frame),
-            Occ(occ) => gen_freq(input, occ.len()),
+            Freq(frame) | Top(frame, _) => frame,
+            Occ(occ) => occ.len(),
         }
     }
 }
+
+fn print_top(freq: &Counts, frame: usize, n: usize, out: &mut dyn Write) -> io::Result<()> {
+    let mut v: Vec<_> = freq.iter().map(|(&code, &count)| (count, code)).collect();
+    v.sort();
+    let total = v.iter().map(|&(count, _)| count).sum::<u32>() as f32;
+    for &(count, key) in v.iter().rev().take(n) {
+        writeln!(out, "{} {:.3}", key.to_string(frame), (count as f32 * 100.) / total)?;
+    }
+    writeln!(out, "")
+}
 static ITEMS: [Item; 7] = [
     Freq(1),
     Freq(2),
@@ -154,43 +186,123 @@ static ITEMS: [Item; 7] = [
 ];
 
 
-fn get_seq<R: std::io::BufRead>(mut r: R, key: &[u8]) -> Vec<u8> {
+fn get_seq<R: std::io::BufRead>(mut r: R, key: &[u8]) -> io::Result<Vec<u8>> {
     let mut res = Vec::with_capacity(65536);
     let mut line = Vec::with_capacity(64);
 
-    loop {
-        match r.read_until(b'\n', &mut line) {
-            Ok(b) if b > 0 => if line.starts_with(key) { break },
-            _ => break,
-        }
+    while r.read_until(b'\n', &mut line)? > 0 {
+        if line.starts_with(key) { break }
         line.clear();
     }
 
     loop {
         line.clear();
-        match r.read_until(b'\n', &mut line) {
-            Ok(b) if b > 0 => res.extend(line[..line.len()-1].iter().cloned().map(Code::encode)),
-            _ => break,
+        match r.read_until(b'\n', &mut line)? {
+            0 => break,
+            _ => res.extend(line[..line.len()-1].iter().cloned().map(Code::encode)),
         }
     }
 
-    res
+    Ok(res)
 }
 
-fn main() {
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: k_nucleotide [-k K]... [--top N] [--threads N] < FASTA
+
+Count the k-mers in the sequence of the >THREE record of the FASTA on stdin,
+and print how often each base and pair of bases turns up, and how many times
+each of the benchmark's five longer sequences does.
+
+  -k K         count the K-mers, K from 1 to 32, rather than those, and print
+               them all from most to least frequent; can be given more than once
+  --top N      with -k, only print the N most frequent of each length
+  --threads N  count on N threads rather than one for each CPU
+  -h, --help   print this message
+  --version    print how this was built
+";
+
+include!("common/main.rs");
+
+fn run() -> io::Result<()> {
+    // `-k K` counts the K-mers in place of the benchmark's usual items, and
+    // `--top N` only prints the N most frequent of each.
+    let mut frames = vec![];
+    let mut top = None;
+    // RAYON_NUM_THREADS sets the default, as for reverse_complement.
+    let mut threads = std::env::var("RAYON_NUM_THREADS").ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0);
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-k") => {
+                frames.push(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&k| k >= 1 && k <= 32)
+                    .ok_or_else(|| invalid_input("-k needs a length from 1 to 32".into()))?);
+            }
+            Some("--top") => {
+                top = Some(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid_input("--top needs a positive number".into()))?);
+            }
+            Some("--threads") => {
+                threads = Some(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid_input("--threads needs a positive number".into()))?);
+            }
+            Some("-h") | Some("--help") => return io::stdout().write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut io::stdout()),
+            _ => return Err(invalid_input(format!("unknown argument {:?}; see --help", arg))),
+        }
+    }
+    if frames.is_empty() && top.is_some() {
+        return Err(invalid_input("--top only applies to -k".into()));
+    }
+    let items: Vec<Item> = if frames.is_empty() {
+        ITEMS.to_vec()
+    } else {
+        let top = top.unwrap_or(usize::max_value());
+        frames.iter().map(|&k| Top(k, top)).collect()
+    };
+
     let stdin = std::io::stdin();
-    let input = get_seq(stdin.lock(), b">THREE");
+    // Rather than wait for someone to type in a FASTA file.
+    if stdin.is_terminal() {
+        return Err(invalid_input("stdin is a terminal, not the FASTA input; run as \
+                                  `k_nucleotide < FILE`, or see --help".into()));
+    }
+    let input = get_seq(stdin.lock(), b">THREE")?;
     let input = Arc::new(input);
-    let pool = CpuPool::new_num_cpus();
+    let threads = threads.unwrap_or_else(num_cpus::get);
+    let pool = CpuPool::new(threads);
 
-    // In reverse to spawn big tasks first
-    let items: Vec<_> = ITEMS.iter().rev().map(|&item| {
-        let input = input.clone();
-        let future_freq = pool.spawn_fn(move || Ok::<_, ()>(item.gen_freq(&input)));
-        (item, future_freq)
+    // In reverse to spawn big tasks first. Each item is counted in parts
+    // on all the threads, so the biggest isn't left to one of them.
+    let items: Vec<_> = items.iter().rev().map(|&item| {
+        let frame = item.frame();
+        let chunks = chunks(input.len(), frame, threads);
+        let shard_bases = if chunks.len() > 1 { SHARD_BASES } else { 0 };
+        let parts: Vec<_> = chunks.into_iter()
+            .map(|range| {
+                let input = input.clone();
+                pool.spawn_fn(move || Ok::<_, ()>(gen_freq(&input[range], frame, shard_bases)))
+            })
+            .collect();
+        (item, parts)
     }).collect();
 
-    for (item, future_freq) in items.into_iter().rev() {
-        item.print(&future_freq.wait().unwrap());
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (item, parts) in items.into_iter().rev() {
+        let parts = parts.into_iter().map(|part| part.wait().unwrap()).collect();
+        item.print(&merge(parts, &pool), &mut out)?;
     }
+    Ok(())
 }
@@ -5,12 +5,17 @@
 // contributed by TeXitoi
 // contributed by Cristi Cobzarenco (@cristicbz)
 
+#[cfg(feature = "png")]
+extern crate png;
 extern crate rayon;
 
 use std::io::Write;
 use std::ops::{Add, Mul, Sub};
+use rayon::Configuration;
 use rayon::prelude::*;
 
+include!("common/alloc.rs");
+
 const MAX_ITER: usize = 50;
 const VLEN: usize = 8;
 const ZEROS: Vecf64 = Vecf64([0.; VLEN]);
@@ -57,10 +62,12 @@ pub struct Mandelbrot8 {
     cr: Vecf64,
     ci: Vecf64,
     ci2: Vecf64,
+
+    max_iter: usize,
 }
 
 impl Mandelbrot8 {
-    pub fn new(ci: Vecf64) -> Self {
+    pub fn new(ci: Vecf64, max_iter: usize) -> Self {
         Mandelbrot8 {
             zr: ZEROS,
             zi: ZEROS,
@@ -70,6 +77,8 @@ impl Mandelbrot8 {
             cr: ZEROS,
             ci: ci,
             ci2: ci * ci,
+
+            max_iter: max_iter,
         }
     }
 
@@ -80,16 +89,55 @@ impl Mandelbrot8 {
         self.ti = self.ci2;
         self.cr = cr;
 
-        self.advance(4);
-        for _ in 0..MAX_ITER / 5 - 1 {
+        // The starting values above are the first iteration.
+        let mut left = self.max_iter - 1;
+        let first = std::cmp::min(4, left);
+        self.advance(first);
+        left -= first;
+        while left > 0 {
             if self.all_diverged() {
                 return 0;
             }
-            self.advance(5);
+            if left >= 5 {
+                self.advance(5);
+                left -= 5;
+            } else {
+                self.advance(left);
+                left = 0;
+            }
         }
         self.to_byte()
     }
 
+    /// The iteration at which each point is first seen to escape, counting
+    /// from 1 the same way as `run`, or max_iter + 1 if it never does.
+    pub fn escape(&mut self, cr: Vecf64, cr2: Vecf64) -> [usize; VLEN] {
+        self.zr = cr;
+        self.zi = self.ci;
+        self.tr = cr2;
+        self.ti = self.ci2;
+        self.cr = cr;
+
+        let max_iter = self.max_iter;
+        let mut escape = [max_iter + 1; VLEN];
+        for i in 1..max_iter + 1 {
+            if i > 1 {
+                self.advance(1);
+            }
+            let mut all_escaped = true;
+            for (e, &t) in escape.iter_mut().zip(&(self.tr + self.ti).0) {
+                if *e > max_iter && t > 4. {
+                    *e = i;
+                }
+                all_escaped &= *e <= max_iter;
+            }
+            if all_escaped {
+                break;
+            }
+        }
+        escape
+    }
+
     fn advance(&mut self, iterations: usize) {
         for _ in 0..iterations {
             self.zi = (self.zr + self.zr) * self.zi + self.ci;
@@ -114,30 +162,191 @@ impl Mandelbrot8 {
 }
 
 
-fn main() {
-    let size = std::env::args().nth(1).and_then(|n| n.parse().ok()).unwrap_or(200);
+#[derive(PartialEq)]
+enum Format {
+    /// The benchmark's bitmap.
+    Pbm,
+    /// Grayscale, by how soon each point escapes.
+    Pgm,
+    /// The same as `Pgm`, as a PNG.
+    Png,
+}
+
+/// The part of the plane to draw: pixel (0, 0) sits at `x0 + y0 i` and
+/// each pixel is `inv` wide.
+struct View {
+    x0: f64,
+    y0: f64,
+    inv: f64,
+    max_iter: usize,
+}
+
+impl View {
+    /// `zoom` 1 shows a square two units wide around `center`; the defaults
+    /// give the benchmark's frame, [-1.5, 0.5) x [-1, 1).
+    fn new(size: usize, center: (f64, f64), zoom: f64, max_iter: usize) -> View {
+        View {
+            x0: center.0 - 1. / zoom,
+            y0: center.1 - 1. / zoom,
+            inv: 2. / (zoom * size as f64),
+            max_iter: max_iter,
+        }
+    }
+
+    fn x(&self, i: usize) -> f64 { i as f64 * self.inv + self.x0 }
+    fn y(&self, j: usize) -> f64 { j as f64 * self.inv + self.y0 }
+}
+
+/// One byte for each pixel, shading the points that escape from white for
+/// the first iteration down to nearly black for the last. The points in the
+/// set are black.
+fn grayscale(size: usize, view: &View, xloc: &[(Vecf64, Vecf64)]) -> Vec<u8> {
+    let mut output = vec![0u8; size * size];
+    output.par_chunks_mut(size)
+        .weight_max()
+        .enumerate()
+        .for_each(|(y, row)| {
+            let mut m = Mandelbrot8::new(Vecf64([view.y(y); VLEN]), view.max_iter);
+            for (&(x, x2), pixels) in xloc.iter().zip(row.chunks_mut(VLEN)) {
+                for (p, &e) in pixels.iter_mut().zip(&m.escape(x, x2)) {
+                    if e <= view.max_iter {
+                        *p = (255 - 255 * (e - 1) / view.max_iter) as u8;
+                    }
+                }
+            }
+        });
+    output
+}
+
+#[cfg(feature = "png")]
+fn write_png<W: Write>(out: W, size: usize, pixels: &[u8]) -> std::io::Result<()> {
+    let mut encoder = png::Encoder::new(out, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let to_io = |e: png::EncodingError| match e {
+        png::EncodingError::IoError(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::Other, e),
+    };
+    let mut writer = encoder.write_header().map_err(&to_io)?;
+    writer.write_image_data(pixels).map_err(&to_io)
+}
+
+#[cfg(not(feature = "png"))]
+fn write_png<W: Write>(_: W, _: usize, _: &[u8]) -> std::io::Result<()> {
+    Err(invalid_input("--format png needs the png feature".into()))
+}
+
+include!("common/main.rs");
+
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: mandelbrot [OPTIONS] [SIZE]
+
+Draw the Mandelbrot set on a SIZE by SIZE grid (default 200, rounded down to a
+multiple of 8), and print it as a PBM bitmap.
+
+  --format FORMAT  pbm, or pgm or png to shade each point by how soon it
+                   escapes (png needs the png feature)
+  --center X,Y     center the view on X,Y rather than -0.5,0
+  --zoom Z         magnify the view Z times
+  --max-iter N     give up on a point after N iterations rather than 50
+  --threads N      use N threads rather than one for each CPU
+  -h, --help       print this message
+  --version        print how this was built
+";
+
+fn run() -> std::io::Result<()> {
+    let mut size = 200;
+    let mut format = Format::Pbm;
+    let mut center = (-0.5, 0.);
+    let mut zoom = 1.;
+    let mut max_iter = MAX_ITER;
+    // RAYON_NUM_THREADS sets the default, as for reverse_complement.
+    let mut threads = std::env::var("RAYON_NUM_THREADS").ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0);
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--format") => {
+                format = match args.next().as_ref().and_then(|s| s.to_str()) {
+                    Some("pbm") => Format::Pbm,
+                    Some("pgm") => Format::Pgm,
+                    Some("png") => Format::Png,
+                    _ => return Err(invalid_input("--format needs pbm, pgm or png".into())),
+                };
+            }
+            Some("--center") => {
+                center = args.next().as_ref().and_then(|s| s.to_str()).and_then(|s| {
+                    let mut it = s.splitn(2, ',').map(|v| v.trim().parse().ok());
+                    Some((it.next()??, it.next()??))
+                }).ok_or_else(|| invalid_input("--center needs x,y".into()))?;
+            }
+            Some("--zoom") => {
+                zoom = args.next().as_ref().and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&z: &f64| z > 0. && z.is_finite())
+                    .ok_or_else(|| invalid_input("--zoom needs a positive number".into()))?;
+            }
+            Some("--max-iter") => {
+                max_iter = args.next().as_ref().and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&m| m > 0)
+                    .ok_or_else(|| invalid_input("--max-iter needs a positive count".into()))?;
+            }
+            Some("--threads") => {
+                threads = Some(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid_input("--threads needs a positive number".into()))?);
+            }
+            Some("-h") | Some("--help") => return std::io::stdout().write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut std::io::stdout()),
+            _ => size = arg.to_str().and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_input(format!("unknown argument {:?}; see --help", arg)))?,
+        }
+    }
+    // Rows are worked out VLEN pixels at a time, so a smaller image is empty.
+    if size < VLEN {
+        return Err(invalid_input(format!("SIZE must be at least {}", VLEN)));
+    }
+    if let Some(n) = threads {
+        rayon::initialize(Configuration::new().set_num_threads(n)).unwrap();
+    }
     let size = size / VLEN * VLEN;
-    let inv = 2. / size as f64;
+    let view = View::new(size, center, zoom, max_iter);
     let mut xloc = vec![(ZEROS, ZEROS); size / VLEN];
     for i in 0..size {
-        let x = i as f64 * inv - 1.5;
+        let x = view.x(i);
         (xloc[i / VLEN].0).0[i % VLEN] = x;
         (xloc[i / VLEN].1).0[i % VLEN] = x * x;
     }
 
+    if format != Format::Pbm {
+        let pixels = grayscale(size, &view, &xloc);
+        let stdout_unlocked = std::io::stdout();
+        let mut stdout = stdout_unlocked.lock();
+        if format == Format::Png {
+            return write_png(stdout, size, &pixels);
+        }
+        write!(stdout, "P5\n{} {}\n255\n", size, size)?;
+        return stdout.write_all(&pixels);
+    }
+
     let mut output = vec![0u8; size * size / VLEN];
     output.par_chunks_mut(size / VLEN)
         .weight_max()
         .enumerate()
         .for_each(|(y, chunk)| {
-            let mut m = Mandelbrot8::new(Vecf64([y as f64 * inv - 1.; VLEN]));
+            let mut m = Mandelbrot8::new(Vecf64([view.y(y); VLEN]), view.max_iter);
             for (&(x, x2), c) in xloc.iter().zip(chunk) {
                 *c = m.run(x, x2);
             }
         });
 
-    println!("P4\n{} {}", size, size);
     let stdout_unlocked = std::io::stdout();
     let mut stdout = stdout_unlocked.lock();
-    stdout.write_all(&output).unwrap();
+    writeln!(stdout, "P4\n{} {}", size, size)?;
+    stdout.write_all(&output)
 }
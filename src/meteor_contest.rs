@@ -4,11 +4,15 @@
 // contributed by the Rust Project Developers
 // contributed by TeXitoi
 
+
+use std::io::{self, Write};
 use std::iter::repeat;
 use std::sync::Arc;
 use std::sync::mpsc::channel;
 use std::thread::spawn;
 
+include!("common/alloc.rs");
+
 //
 // Utilities.
 //
@@ -191,13 +195,13 @@ fn to_vec(raw_sol: &List<u64>) -> Vec<u8> {
 }
 
 // Prints a solution in Vec<u8> form.
-fn print_sol(sol: &Vec<u8>) {
+fn print_sol(out: &mut dyn Write, sol: &Vec<u8>) -> io::Result<()> {
     for (i, c) in sol.iter().enumerate() {
-        if i % 5 == 0 { println!(""); }
-        if (i + 5) % 10 == 0 { print!(" "); }
-        print!("{} ", *c as char);
+        if i % 5 == 0 { writeln!(out, "")?; }
+        if (i + 5) % 10 == 0 { write!(out, " ")?; }
+        write!(out, "{} ", *c as char)?;
     }
-    println!("");
+    writeln!(out, "")
 }
 
 // The data managed during the search
@@ -289,12 +293,37 @@ fn par_search(masks: Vec<Vec<Vec<u64>>>) -> Data {
     data
 }
 
-fn main () {
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: meteor_contest [N]
+
+Find every way to fill the meteor puzzle's board, and print how many there are
+and the smallest and largest. N, the number of solutions the benchmark asks
+for, is accepted but they are always all found.
+";
+
+include!("common/main.rs");
+
+fn run() -> io::Result<()> {
+    for arg in std::env::args_os().skip(1) {
+        match arg.to_str() {
+            Some("-h") | Some("--help") => return io::stdout().write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut io::stdout()),
+            Some(s) if s.parse::<usize>().is_ok() => {}
+            _ => return Err(invalid_input(format!("unknown argument {:?}; see --help", arg))),
+        }
+    }
     let mut masks = make_masks();
     filter_masks(&mut masks);
     let data = par_search(masks);
-    println!("{} solutions found", data.nb);
-    print_sol(&data.min);
-    print_sol(&data.max);
-    println!("");
+    print_results(&data)
+}
+
+fn print_results(data: &Data) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "{} solutions found", data.nb)?;
+    print_sol(&mut out, &data.min)?;
+    print_sol(&mut out, &data.max)?;
+    writeln!(out, "")
 }
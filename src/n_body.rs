@@ -5,6 +5,14 @@
 // contributed by Matt Brubeck
 // contributed by TeXitoi
 
+extern crate toml;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+include!("common/alloc.rs");
+
 const PI: f64 = 3.141592653589793;
 const SOLAR_MASS: f64 = 4.0 * PI * PI;
 const YEAR: f64 = 365.24;
@@ -66,7 +74,7 @@ struct Planet {
     mass: f64,
 }
 
-fn advance(bodies: &mut [Planet;N_BODIES], dt: f64, steps: i32) {
+fn advance(bodies: &mut [Planet], dt: f64, steps: i32) {
     for _ in 0..steps {
         let mut b_slice: &mut [_] = bodies;
         while let Some((bi, tail)) = {b_slice}.split_first_mut() {
@@ -101,7 +109,7 @@ fn advance(bodies: &mut [Planet;N_BODIES], dt: f64, steps: i32) {
     }
 }
 
-fn energy(bodies: &[Planet;N_BODIES]) -> f64 {
+fn energy(bodies: &[Planet]) -> f64 {
     bodies.iter().enumerate().fold(0.0, |e, (i, bi)| {
         let ei = (bi.vx * bi.vx + bi.vy * bi.vy + bi.vz * bi.vz) * bi.mass / 2.0;
         let ei2 = bodies[i + 1..].iter().fold(0.0, |ej, bj| {
@@ -115,7 +123,9 @@ fn energy(bodies: &[Planet;N_BODIES]) -> f64 {
     })
 }
 
-fn offset_momentum(bodies: &mut [Planet;N_BODIES]) {
+/// Change the velocity of the first body, the sun for the benchmark, to make
+/// the total momentum zero.
+fn offset_momentum(bodies: &mut [Planet]) {
     let (px, py, pz) = bodies.iter()
         .fold((0.0, 0.0, 0.0), |(px, py, pz), bi| 
             (px + bi.vx * bi.mass,
@@ -123,22 +133,101 @@ fn offset_momentum(bodies: &mut [Planet;N_BODIES]) {
              pz + bi.vz * bi.mass));
 
     let sun = &mut bodies[0];
-    sun.vx = - px / SOLAR_MASS;
-    sun.vy = - py / SOLAR_MASS;
-    sun.vz = - pz / SOLAR_MASS;
+    sun.vx -= px / sun.mass;
+    sun.vy -= py / sun.mass;
+    sun.vz -= pz / sun.mass;
 }
 
-fn main() {
-    let n = std::env::args_os().nth(1)
-        .and_then(|s| s.into_string().ok())
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(1000);
-    let mut bodies = BODIES;
+/// Read the bodies from a TOML file with a `[[body]]` table for each one, in
+/// the same units as `BODIES`: solar masses, AU and AU per day.
+///
+/// ```toml
+/// [[body]]
+/// name = "Sun"
+/// mass = 1.0
+/// position = [0.0, 0.0, 0.0]
+/// velocity = [0.0, 0.0, 0.0]
+/// ```
+///
+/// The name is optional. The first body's velocity is adjusted to make the
+/// total momentum zero, the same way the sun's is.
+fn read_bodies(path: &Path) -> Result<Vec<Planet>, String> {
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| e.to_string())?;
+    let file: toml::Value = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+    let bodies = file.get("body").and_then(|b| b.as_array())
+        .filter(|b| !b.is_empty())
+        .ok_or("no [[body]] tables".to_string())?;
+    bodies.iter().enumerate().map(|(i, body)| {
+        let name = body.get("name").and_then(|n| n.as_str())
+            .map_or(format!("body {}", i + 1), |n| n.to_string());
+        // Integers are fine too, so `mass = 1` doesn't need a `.0`.
+        let number = |value: &toml::Value| {
+            value.as_float().or(value.as_integer().map(|n| n as f64))
+        };
+        let vector = |field: &str| {
+            body.get(field).and_then(|v| v.as_array())
+                .filter(|v| v.len() == 3)
+                .and_then(|v| Some((number(&v[0])?, number(&v[1])?, number(&v[2])?)))
+                .ok_or(format!("{}: {} isn't a list of 3 numbers", name, field))
+        };
+        let mass = body.get("mass").and_then(&number).filter(|&m| m > 0.)
+            .ok_or(format!("{}: mass isn't a positive number", name))?;
+        let (x, y, z) = vector("position")?;
+        let (vx, vy, vz) = vector("velocity")?;
+        Ok(Planet {
+            x: x, y: y, z: z,
+            vx: vx * YEAR, vy: vy * YEAR, vz: vz * YEAR,
+            mass: mass * SOLAR_MASS,
+        })
+    }).collect()
+}
+
+include!("common/main.rs");
+
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: n_body [--bodies FILE] [STEPS]
+
+Simulate the Sun and the outer planets for STEPS steps (default 1000), and print
+the energy of the system before and after.
+
+  --bodies FILE  simulate the bodies listed in a TOML file instead
+  -h, --help     print this message
+  --version      print how this was built
+";
+
+fn run() -> io::Result<()> {
+    let mut n = 1000;
+    let mut path = None;
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--bodies") => {
+                let file = args.next()
+                    .ok_or_else(|| invalid_input("--bodies needs a file name".into()))?;
+                path = Some(PathBuf::from(file));
+            }
+            Some("-h") | Some("--help") => return io::stdout().write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut io::stdout()),
+            _ => n = arg.to_str().and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_input(format!("unknown argument {:?}; see --help", arg)))?,
+        }
+    }
+    let mut bodies = match path {
+        Some(path) => read_bodies(&path)
+            .map_err(|e| invalid_input(format!("{}: {}", path.display(), e)))?,
+        None => BODIES.to_vec(),
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
 
     offset_momentum(&mut bodies);
-    println!("{:.9}", energy(&bodies));
+    writeln!(out, "{:.9}", energy(&bodies))?;
 
     advance(&mut bodies, 0.01, n);
 
-    println!("{:.9}", energy(&bodies));
+    writeln!(out, "{:.9}", energy(&bodies))
 }
@@ -7,23 +7,44 @@
 
 #![allow(non_camel_case_types)]
 
+
 use std::os::raw::{c_int, c_ulong, c_void};
 use std::mem::uninitialized;
 use std::cmp::Ordering;
+use std::io::{self, Write};
+
+include!("common/alloc.rs");
+
+include!("common/main.rs");
+
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: pidigits [N]
 
-fn main() {
-    let n = std::env::args_os().nth(1)
-        .and_then(|s| s.into_string().ok())
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(27);
+Print the first N digits of pi (default 27), ten to a line.
+";
+
+fn run() -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut n = 27;
+    for arg in std::env::args_os().skip(1) {
+        match arg.to_str() {
+            Some("-h") | Some("--help") => return out.write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut out),
+            _ => n = arg.to_str().and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_input(format!("unknown argument {:?}; see --help", arg)))?,
+        }
+    }
     for (i, d) in Context::new().enumerate().take(n) {
-        print!("{}", d);
-        if (i + 1) % 10 == 0 { println!("\t:{}", i + 1); }
+        write!(out, "{}", d)?;
+        if (i + 1) % 10 == 0 { writeln!(out, "\t:{}", i + 1)?; }
     }
     if n % 10 != 0 {
-        for _ in n % 10 .. 10 { print!(" "); }
-        println!("\t:{}", n);
+        for _ in n % 10 .. 10 { write!(out, " ")?; }
+        writeln!(out, "\t:{}", n)?;
     }
+    Ok(())
 }
 
 pub struct Context {
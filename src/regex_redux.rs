@@ -10,31 +10,272 @@
 extern crate regex;
 
 use std::borrow::Cow;
+use std::cmp::{max, min};
+use std::convert::TryFrom;
 use std::fs::File;
-use std::io::{self, Read};
+use std::io::{self, IsTerminal, Read, Write};
 use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use std::thread;
+use regex::bytes::Regex;
+
+include!("common/alloc.rs");
 
 macro_rules! regex { ($re:expr) => { ::regex::bytes::Regex::new($re).unwrap() } }
 
-/// Read the input into memory.
-fn read() -> io::Result<Vec<u8>> {
+/// Size of each piece of input read with `--chunked`.
+const CHUNK_SIZE: usize = 1 << 20;
+/// Number of pieces each thread can fall behind the reader by.
+const QUEUE_LEN: usize = 4;
+
+/// Open stdin as a `File`, so its size can be looked up.
+#[cfg(unix)]
+fn open_stdin() -> io::Result<File> {
+    File::open("/dev/stdin")
+}
+
+/// Windows has no /dev/stdin, so duplicate the handle instead.
+#[cfg(windows)]
+fn open_stdin() -> io::Result<File> {
+    use std::os::windows::io::AsHandle;
+    Ok(File::from(io::stdin().as_handle().try_clone_to_owned()?))
+}
+
+/// Read the input into memory, or return `None` if it's too big to fit, as
+/// an input over 4 GiB is on a 32-bit target.
+fn read() -> io::Result<Option<Vec<u8>>> {
     // Pre-allocate a buffer based on the input file size.
-    let mut stdin = File::open("/dev/stdin")?;
-    let size = stdin.metadata()?.len() as usize;
-    let mut buf = Vec::with_capacity(size + 1);
+    let mut stdin = open_stdin()?;
+    let size = stdin.metadata()?.len();
+    let mut buf = Vec::new();
+    match usize::try_from(size).ok().and_then(|size| size.checked_add(1)) {
+        Some(size) if buf.try_reserve_exact(size).is_ok() => {}
+        _ => return Ok(None),
+    }
 
     stdin.read_to_end(&mut buf)?;
-    Ok(buf)
+    Ok(Some(buf))
 }
 
-fn main() {
-    let mut seq = read().unwrap();
-    let ilen = seq.len();
+/// How far a pattern's matches can reach, which says how much of each
+/// chunk has to be kept for the next one with `--chunked`.
+#[derive(Clone, Copy)]
+enum Reach {
+    /// No match is longer than this.
+    Bytes(usize),
+    /// Every match ends at the first `close` byte after it starts, and one
+    /// can begin at any `open` byte that has no `close` byte after it.
+    Delimited(u8, u8),
+}
+
+/// A regex replacement done a chunk at a time. Whatever might be the start
+/// of a match running on into the next chunk is kept back until it comes.
+struct Replacer {
+    re: Regex,
+    replacement: &'static [u8],
+    reach: Reach,
+    carry: Vec<u8>,
+    /// Bytes left out of `carry` by `shrink`.
+    dropped: usize,
+}
+
+impl Replacer {
+    fn new(re: Regex, replacement: &'static [u8], reach: Reach) -> Replacer {
+        Replacer { re: re, replacement: replacement, reach: reach, carry: vec![], dropped: 0 }
+    }
+
+    /// Replace the matches in `input`, after what was kept back last time,
+    /// and add everything that can't change any more to `out`. With `last`,
+    /// there's no more input and nothing is kept back. Returns the number
+    /// of bytes `shrink` left out that belong in `out` after all.
+    fn feed(&mut self, input: &[u8], last: bool, out: &mut Vec<u8>) -> usize {
+        self.carry.extend_from_slice(input);
+        let window = &self.carry[..];
+        // Matches can only be trusted if they start before `limit`, since
+        // one starting after it might turn out longer once there's more.
+        let limit = match self.reach {
+            Reach::Bytes(n) if !last => window.len().saturating_sub(n - 1),
+            _ => window.len(),
+        };
+        let mut done = 0;
+        let mut matched_carry = false;
+        for m in self.re.find_iter(window) {
+            if m.start() >= limit {
+                break;
+            }
+            matched_carry |= m.start() == 0;
+            out.extend_from_slice(&window[done..m.start()]);
+            out.extend_from_slice(self.replacement);
+            done = m.end();
+        }
+        let keep = match self.reach {
+            _ if last => window.len(),
+            Reach::Bytes(_) => max(done, limit),
+            Reach::Delimited(open, close) => {
+                let tail = window[done..].iter().rposition(|&b| b == close)
+                    .map_or(done, |i| done + i + 1);
+                if open == close && tail > done {
+                    tail - 1
+                } else {
+                    window[tail..].iter().position(|&b| b == open)
+                        .map_or(window.len(), |i| tail + i)
+                }
+            }
+        };
+        out.extend_from_slice(&window[done..keep]);
+        self.carry.drain(..keep);
+        // The bytes left out were in the middle of something kept back. If
+        // that's still kept back they stay dropped, and if it matched they're
+        // gone, but otherwise they were part of the output.
+        if keep == 0 || matched_carry {
+            if matched_carry { self.dropped = 0; }
+            0
+        } else {
+            std::mem::replace(&mut self.dropped, 0)
+        }
+    }
+
+    /// Leave out all but the first two bytes of what's kept back, if only
+    /// the length of the output matters. That's enough to tell how a
+    /// delimited match starts, and what comes between the delimiters doesn't
+    /// change how it ends.
+    fn shrink(&mut self) {
+        if let Reach::Delimited(..) = self.reach {
+            if self.carry.len() > 2 {
+                self.dropped += self.carry.len() - 2;
+                self.carry.truncate(2);
+            }
+        }
+    }
+}
+
+/// Count the matches of `re`, which are never longer than `len` bytes, in
+/// the chunks from `rx`.
+fn count_chunks(re: Regex, len: usize, rx: Receiver<Arc<Vec<u8>>>) -> u64 {
+    let mut count = 0;
+    let mut carry = vec![];
+    // Where the search should start in `carry`, after the last match.
+    let mut start = 0;
+    let mut search = |carry: &mut Vec<u8>, last: bool| {
+        let limit = if last { carry.len() } else { carry.len().saturating_sub(len - 1) };
+        let mut end = start;
+        for m in re.find_iter(&carry[start..]) {
+            if start + m.start() >= limit {
+                break;
+            }
+            count += 1;
+            end = start + m.end();
+        }
+        let keep = max(end, limit);
+        carry.drain(..keep);
+        start = end - min(end, keep);
+    };
+    for chunk in rx {
+        carry.extend_from_slice(&chunk);
+        search(&mut carry, false);
+    }
+    search(&mut carry, true);
+    count
+}
+
+/// Do the same as `main`, but reading the input a chunk at a time, so that
+/// it never has to be all in memory. Returns the count for each variant and
+/// the input length after each step, which can be more than a `usize` holds.
+fn chunked(variants: Vec<Regex>, substs: Vec<(Regex, &'static [u8], Reach)>)
+           -> io::Result<(Vec<(String, u64)>, u64, u64, u64)> {
+    let mut txs: Vec<SyncSender<Arc<Vec<u8>>>> = vec![];
+    let counts: Vec<_> = variants.into_iter().map(|variant| {
+        let (tx, rx) = sync_channel(QUEUE_LEN);
+        txs.push(tx);
+        let restr = variant.to_string();
+        // Every variant matches exactly eight bytes.
+        (restr, thread::spawn(move || count_chunks(variant, 8, rx)))
+    }).collect();
+
+    let (tx, rx) = sync_channel::<Arc<Vec<u8>>>(QUEUE_LEN);
+    txs.push(tx);
+    let replaced = thread::spawn(move || {
+        let mut replacers: Vec<_> = substs.into_iter()
+            .map(|(re, replacement, reach)| Replacer::new(re, replacement, reach))
+            .collect();
+        // Only the length of the last step's output is needed, so it can
+        // shrink what it keeps back while it waits for the closing '|'.
+        let mut len = 0;
+        let mut step = |replacers: &mut [Replacer], input: &[u8], last: bool| {
+            let mut input = input.to_vec();
+            let mut out = vec![];
+            for replacer in replacers.iter_mut() {
+                len += replacer.feed(&input, last, &mut out) as u64;
+                std::mem::swap(&mut input, &mut out);
+                out.clear();
+            }
+            len += input.len() as u64;
+            replacers.last_mut().unwrap().shrink();
+        };
+        for chunk in rx {
+            step(&mut replacers, &chunk, false);
+        }
+        step(&mut replacers, &[], true);
+        len
+    });
 
     // Remove headers and newlines.
-    seq = regex!(">[^\n]*\n|\n").replace_all(&seq, &b""[..]).into_owned();
-    let clen = seq.len();
+    let mut clean = Replacer::new(regex!(">[^\n]*\n|\n"), b"", Reach::Delimited(b'>', b'\n'));
+    let mut stdin = io::stdin();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let (mut ilen, mut clen) = (0, 0);
+    loop {
+        let n = stdin.read(&mut buf)?;
+        let mut seq = Vec::with_capacity(n);
+        clean.feed(&buf[..n], n == 0, &mut seq);
+        ilen += n as u64;
+        clen += seq.len() as u64;
+        let seq = Arc::new(seq);
+        for tx in &txs {
+            tx.send(seq.clone()).unwrap();
+        }
+        if n == 0 {
+            break;
+        }
+    }
+    drop(txs);
+
+    let counts = counts.into_iter().map(|(restr, count)| (restr, count.join().unwrap())).collect();
+    Ok((counts, ilen, clen, replaced.join().unwrap()))
+}
+
+include!("common/main.rs");
+
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: regex_redux [--chunked] < FASTA
+
+Count how often each of the benchmark's patterns turns up in the FASTA on
+stdin, make its substitutions, and print the counts and the lengths of the
+input, of its sequence data and of the result.
+
+  --chunked   read the input a piece at a time rather than all at once
+  -h, --help  print this message
+  --version   print how this was built
+";
+
+fn run() -> io::Result<()> {
+    let mut chunked_arg = false;
+    for arg in std::env::args_os().skip(1) {
+        match arg.to_str() {
+            // Read the input in chunks, instead of all at once. That's also
+            // done if it's too big to hold in memory.
+            Some("--chunked") => chunked_arg = true,
+            Some("-h") | Some("--help") => return io::stdout().write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut io::stdout()),
+            _ => return Err(invalid_input(format!("unknown argument {:?}; see --help", arg))),
+        }
+    }
+    // Rather than wait for someone to type in a FASTA file.
+    if io::stdin().is_terminal() {
+        return Err(invalid_input("stdin is a terminal, not the FASTA input; run as \
+                                  `regex_redux < FILE`, or see --help".into()));
+    }
 
     // Search for occurrences of the following patterns:
     let variants = vec![
@@ -49,6 +290,34 @@ fn main() {
         regex!("agggtaa[cgt]|[acg]ttaccct"),
     ];
 
+    // Replace the following patterns, one at a time:
+    let substs = vec![
+        (regex!("tHa[Nt]"), &b"<4>"[..], Reach::Bytes(4)),
+        (regex!("aND|caN|Ha[DS]|WaS"), &b"<3>"[..], Reach::Bytes(3)),
+        (regex!("a[NSt]|BY"), &b"<2>"[..], Reach::Bytes(2)),
+        (regex!("<[^>]*>"), &b"|"[..], Reach::Delimited(b'<', b'>')),
+        (regex!("\\|[^|][^|]*\\|"), &b"-"[..], Reach::Delimited(b'|', b'|')),
+    ];
+
+    let input = if chunked_arg { None } else { read()? };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut seq = match input {
+        Some(seq) => seq,
+        None => {
+            let (counts, ilen, clen, rlen) = chunked(variants, substs)?;
+            for (variant, count) in counts {
+                writeln!(out, "{} {}", variant, count)?;
+            }
+            return writeln!(out, "\n{}\n{}\n{}", ilen, clen, rlen);
+        }
+    };
+    let ilen = seq.len();
+
+    // Remove headers and newlines.
+    seq = regex!(">[^\n]*\n|\n").replace_all(&seq, &b""[..]).into_owned();
+    let clen = seq.len();
+
     // Count each pattern in parallel.  Use an Arc (atomic reference-counted
     // pointer) to share the sequence between threads without copying it.
     let seq_arc = Arc::new(seq);
@@ -60,27 +329,18 @@ fn main() {
         counts.push((restr, future));
     }
 
-    // Replace the following patterns, one at a time:
-    let substs = vec![
-        (regex!("tHa[Nt]"), &b"<4>"[..]),
-        (regex!("aND|caN|Ha[DS]|WaS"), &b"<3>"[..]),
-        (regex!("a[NSt]|BY"), &b"<2>"[..]),
-        (regex!("<[^>]*>"), &b"|"[..]),
-        (regex!("\\|[^|][^|]*\\|"), &b"-"[..]),
-    ];
-
     // Use Cow here to avoid one extra copy of the sequence, by borrowing from
     // the Arc during the first iteration.
     let mut seq = Cow::Borrowed(&seq_arc[..]);
 
     // Perform the replacements in sequence:
-    for (re, replacement) in substs {
+    for (re, replacement, _) in substs {
         seq = Cow::Owned(re.replace_all(&seq, replacement).into_owned());
     }
 
     // Print the results:
     for (variant, count) in counts {
-        println!("{} {}", variant, count.join().unwrap());
+        writeln!(out, "{} {}", variant, count.join().unwrap())?;
     }
-    println!("\n{}\n{}\n{}", ilen, clen, seq.len());
+    writeln!(out, "\n{}\n{}\n{}", ilen, clen, seq.len())
 }
@@ -6,12 +6,49 @@
 // contributed by TeXitoi
 // contributed by Matt Brubeck
 
+extern crate flate2;
+extern crate memchr;
+extern crate regex;
+#[cfg(not(feature = "std-threads"))]
 extern crate rayon;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(feature = "uring")]
+extern crate io_uring;
+#[cfg(all(feature = "uring", not(target_os = "linux")))]
+compile_error!("the uring feature only works on Linux");
+#[cfg(all(target_os = "wasi", not(feature = "std-threads")))]
+compile_error!("wasi has no threads for rayon, so build with the std-threads feature");
 
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, BufWriter, IoSlice, IsTerminal, Read, Seek, SeekFrom, Write};
 use std::{cmp, io};
-use std::fs::File;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
 use std::mem::replace;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::mpsc::{Receiver, TryRecvError};
+#[cfg(not(target_os = "wasi"))]
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use flate2::bufread::MultiGzDecoder;
+use memchr::{memchr, memchr_iter, memmem, memrchr};
+use regex::bytes::Regex;
+#[cfg(not(feature = "std-threads"))]
+use rayon::{Configuration, ThreadPool};
+#[cfg(feature = "uring")]
+use io_uring::{opcode, types, IoUring};
+#[cfg(feature = "uring")]
+use std::os::unix::io::AsRawFd;
+#[cfg(target_arch = "x86")]
+use std::arch::x86 as arch;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64 as arch;
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64 as arch;
+
+include!("common/alloc.rs");
 
 /// This controls the size of reads from the input. Chosen to match the C entry.
 const READ_SIZE: usize = 16 * 1024;
@@ -19,32 +56,69 @@ const READ_SIZE: usize = 16 * 1024;
 /// Length of a normal line including the terminating \n.
 const LINE_LEN: usize = 61;
 
-/// Chunks larger than this will be split into separate parallel tasks.
-const SEQUENTIAL_SIZE: usize = 2048;
+/// Chunks smaller than this are never split into separate parallel tasks.
+/// Under Miri, small enough that the short inputs it can get through in
+/// reasonable time are split too.
+#[cfg(not(miri))]
+const MIN_SEQUENTIAL_SIZE: usize = 2048;
+#[cfg(miri)]
+const MIN_SEQUENTIAL_SIZE: usize = 128;
+
+/// How many parallel tasks to split each sequence into per thread.
+#[cfg(not(feature = "std-threads"))]
+const TASKS_PER_THREAD: usize = 4;
+
+/// Size of the reads from the input in out-of-core mode, and of the batches of
+/// small records read from a stream.
+#[cfg(not(miri))]
+const CHUNK_SIZE: usize = 1 << 20;
+#[cfg(miri)]
+const CHUNK_SIZE: usize = 4096;
+
+/// Most batches of records to queue up between the reading, reversing and
+/// writing threads.
+#[cfg(not(target_os = "wasi"))]
+const QUEUE_LEN: usize = 16;
+
+/// Number of reads to keep queued ahead of the parser with io_uring.
+#[cfg(feature = "uring")]
+const URING_DEPTH: usize = 4;
+
+/// How to choose the case of each byte of the output.
+#[derive(Clone, Copy, PartialEq)]
+enum Case {
+    /// Complements are uppercase, and bytes that aren't codes are unchanged.
+    Normal,
+    /// Lowercase codes have lowercase complements.
+    Preserve,
+    /// Every letter is uppercase.
+    Upper,
+    /// Every letter is lowercase.
+    Lower,
+}
+
+include!("common/iupac.rs");
 
-/// Lookup table to find the complement of a single FASTA code.
-fn build_table() -> [u8; 256] {
+/// Lookup table to find the complement of a single FASTA code, adjusted for
+/// the case and complement options.
+fn build_table(options: &Options) -> [u8; 256] {
     let mut table = [0; 256];
     for (i, x) in table.iter_mut().enumerate() {
-        *x = match i as u8 as char {
-            'A' | 'a' => 'T',
-            'C' | 'c' => 'G',
-            'G' | 'g' => 'C',
-            'T' | 't' => 'A',
-            'U' | 'u' => 'A',
-            'M' | 'm' => 'K',
-            'R' | 'r' => 'Y',
-            'W' | 'w' => 'W',
-            'S' | 's' => 'S',
-            'Y' | 'y' => 'R',
-            'K' | 'k' => 'M',
-            'V' | 'v' => 'B',
-            'H' | 'h' => 'D',
-            'D' | 'd' => 'H',
-            'B' | 'b' => 'V',
-            'N' | 'n' => 'N',
-            i => i,
-        } as u8;
+        let c = i as u8;
+        *x = if !options.complement {
+            c
+        } else if options.rna && c.to_ascii_uppercase() == b'A' {
+            b'U'
+        } else {
+            IUPAC_TABLE[i]
+        };
+        match options.case {
+            Case::Normal => {}
+            Case::Preserve if c.is_ascii_lowercase() => *x = x.to_ascii_lowercase(),
+            Case::Preserve => {}
+            Case::Upper => *x = x.to_ascii_uppercase(),
+            Case::Lower => *x = x.to_ascii_lowercase(),
+        }
     }
     table
 }
@@ -76,54 +150,292 @@ impl<'a, T> SplitOff for &'a mut [T] {
 
 /// Compute the reverse complement for two contiguous chunks without line breaks.
 fn reverse_chunks(left: &mut [u8], right: &mut [u8], table: &[u8; 256]) {
-    for (x, y) in left.iter_mut().zip(right.iter_mut().rev()) {
+    let mut done = reverse_chunks_simd(left, right, table);
+    let right_len = right.len() - done;
+    done += reverse_chunks_swar(&mut left[done..], &mut right[..right_len], table);
+    let right_len = right.len() - done;
+    #[cfg(not(feature = "fast-unsafe"))]
+    for (x, y) in left[done..].iter_mut().zip(right[..right_len].iter_mut().rev()) {
         *y = table[replace(x, table[*y as usize]) as usize];
     }
+    // Lookups in `table` are indexed by a `u8`, so they never need a bounds
+    // check either way.
+    #[cfg(feature = "fast-unsafe")]
+    for i in 0..cmp::min(left.len() - done, right_len) {
+        debug_assert!(done + i < left.len() && right_len - 1 - i < right.len());
+        unsafe {
+            let x = left.get_unchecked_mut(done + i);
+            let y = right.get_unchecked_mut(right_len - 1 - i);
+            *y = table[replace(x, table[*y as usize]) as usize];
+        }
+    }
 }
 
-/// Compute the reverse complement on chunks from opposite ends of a sequence.
+/// Run the best vectorized version of `reverse_chunks` this CPU supports on as
+/// much of the chunks as it can, returning the number of bytes processed from
+/// the start of `left` and from the end of `right`.
 ///
-/// `left` must start at the beginning of a line. If there are an odd number of
-/// bytes, `right` will initially be 1 byte longer than `left`; otherwise they
-/// will have equal lengths.
-fn reverse_complement_left_right(mut left: &mut [u8],
-                                 mut right: &mut [u8],
-                                 trailing_len: usize,
-                                 table: &[u8; 256]) {
-    let len = left.len();
-    if len <= SEQUENTIAL_SIZE {
-        // Each iteration swaps one line from the start of the sequence with one
-        // from the end.
-        while left.len() > 0  || right.len() > 0 {
-            // Get the chunk up to the newline in `right`.
-            let mut a = left.split_off_left(trailing_len);
-            let mut b = right.split_off_right(trailing_len);
-            right.split_off_right(1); // Skip the newline in `right`.
+/// On x86 that's AVX2 for 32-byte blocks, then SSSE3 for a last 16-byte one,
+/// picked when the program runs so that one binary suits any CPU.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn reverse_chunks_simd(left: &mut [u8], right: &mut [u8], table: &[u8; 256]) -> usize {
+    let mut done = 0;
+    if is_x86_feature_detected!("avx2") {
+        done = unsafe { reverse_chunks_avx2(left, right, table) };
+    }
+    if is_x86_feature_detected!("ssse3") {
+        let right_len = right.len() - done;
+        done += unsafe { reverse_chunks_ssse3(&mut left[done..], &mut right[..right_len], table) };
+    }
+    done
+}
+
+#[cfg(target_arch = "aarch64")]
+fn reverse_chunks_simd(left: &mut [u8], right: &mut [u8], table: &[u8; 256]) -> usize {
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        unsafe { reverse_chunks_neon(left, right, table) }
+    } else {
+        0
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn reverse_chunks_simd(_: &mut [u8], _: &mut [u8], _: &[u8; 256]) -> usize {
+    0
+}
+
+/// `reverse_chunks` for 8-byte words, which works on any target and for any
+/// bytes: each word's bytes are complemented through `table`, and its order
+/// reversed with a single byte swap.
+fn reverse_chunks_swar(left: &mut [u8], right: &mut [u8], table: &[u8; 256]) -> usize {
+    let complement = |x: &[u8]| {
+        let mut word = [0; 8];
+        for (w, &b) in word.iter_mut().zip(x) {
+            *w = table[b as usize];
+        }
+        u64::from_ne_bytes(word).swap_bytes().to_ne_bytes()
+    };
+    let len = cmp::min(left.len(), right.len());
+    let mut i = 0;
+    while i + 8 <= len {
+        let j = right.len() - i - 8;
+        let a = complement(&left[i..i + 8]);
+        let b = complement(&right[j..j + 8]);
+        left[i..i + 8].copy_from_slice(&b);
+        right[j..j + 8].copy_from_slice(&a);
+        i += 8;
+    }
+    i
+}
+
+/// Look up the complement of 16 bytes at once with `pshufb`.
+///
+/// All nucleotide codes, upper or lower case, lie in 0x40..0x80, so `table` for
+/// that range is four 16-entry tables indexed by the low nibble, one for each
+/// value of the high nibble. Returns `None` if any byte is outside that range.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "ssse3")]
+unsafe fn complement_ssse3(x: arch::__m128i, table: &[u8; 256]) -> Option<arch::__m128i> {
+    use arch::*;
+    let t = table.as_ptr().offset(0x40) as *const __m128i;
+    let valid = _mm_cmpeq_epi8(_mm_and_si128(x, _mm_set1_epi8(0xC0u8 as i8)),
+                               _mm_set1_epi8(0x40));
+    if _mm_movemask_epi8(valid) != 0xFFFF {
+        return None;
+    }
+    let select = |bit: i8, a, b| {
+        let mask = _mm_cmpeq_epi8(_mm_and_si128(x, _mm_set1_epi8(bit)), _mm_set1_epi8(bit));
+        _mm_or_si128(_mm_and_si128(mask, b), _mm_andnot_si128(mask, a))
+    };
+    let upper = select(0x10, _mm_shuffle_epi8(_mm_loadu_si128(t), x),
+                             _mm_shuffle_epi8(_mm_loadu_si128(t.offset(1)), x));
+    let lower = select(0x10, _mm_shuffle_epi8(_mm_loadu_si128(t.offset(2)), x),
+                             _mm_shuffle_epi8(_mm_loadu_si128(t.offset(3)), x));
+    Some(select(0x20, upper, lower))
+}
 
-            // If we've reached the middle of the sequence here and there is an
-            // odd number of bytes remaining, the odd one will be on the right.
-            if b.len() > a.len() {
-                let mid = b.split_off_left(1);
-                mid[0] = table[mid[0] as usize];
+/// `reverse_chunks` for 16-byte blocks using SSSE3 shuffles.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "ssse3")]
+unsafe fn reverse_chunks_ssse3(left: &mut [u8], right: &mut [u8], table: &[u8; 256]) -> usize {
+    use arch::*;
+    let reverse = _mm_set_epi8(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+    let len = cmp::min(left.len(), right.len());
+    let mut i = 0;
+    while i + 16 <= len {
+        let x = left.as_mut_ptr().offset(i as isize) as *mut __m128i;
+        let y = right.as_mut_ptr().offset((right.len() - i - 16) as isize) as *mut __m128i;
+        match (complement_ssse3(_mm_loadu_si128(x), table),
+               complement_ssse3(_mm_loadu_si128(y), table)) {
+            (Some(a), Some(b)) => {
+                _mm_storeu_si128(x, _mm_shuffle_epi8(b, reverse));
+                _mm_storeu_si128(y, _mm_shuffle_epi8(a, reverse));
             }
+            _ => break,
+        }
+        i += 16;
+    }
+    i
+}
 
-            reverse_chunks(a, b, table);
+/// `complement_ssse3` for 32 bytes at once. `t` is the four 16-entry tables,
+/// each repeated in both 128-bit lanes since `vpshufb` can't cross them.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn complement_avx2(x: arch::__m256i, t: &[arch::__m256i; 4]) -> Option<arch::__m256i> {
+    use arch::*;
+    let valid = _mm256_cmpeq_epi8(_mm256_and_si256(x, _mm256_set1_epi8(0xC0u8 as i8)),
+                                  _mm256_set1_epi8(0x40));
+    if _mm256_movemask_epi8(valid) != -1 {
+        return None;
+    }
+    let select = |bit: i8, a, b| {
+        let mask = _mm256_cmpeq_epi8(_mm256_and_si256(x, _mm256_set1_epi8(bit)),
+                                     _mm256_set1_epi8(bit));
+        _mm256_blendv_epi8(a, b, mask)
+    };
+    let upper = select(0x10, _mm256_shuffle_epi8(t[0], x), _mm256_shuffle_epi8(t[1], x));
+    let lower = select(0x10, _mm256_shuffle_epi8(t[2], x), _mm256_shuffle_epi8(t[3], x));
+    Some(select(0x20, upper, lower))
+}
 
-            // Get the chunk up to the newline in `left`.
-            let n = LINE_LEN - 1 - trailing_len;
-            a = left.split_off_left(n);
-            b = right.split_off_right(n);
-            left.split_off_left(1); // Skip the newline in `left`.
+/// `reverse_chunks` for 32-byte blocks using AVX2 shuffles.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn reverse_chunks_avx2(left: &mut [u8], right: &mut [u8], table: &[u8; 256]) -> usize {
+    use arch::*;
+    let t = table.as_ptr().offset(0x40) as *const __m128i;
+    let t = [_mm256_broadcastsi128_si256(_mm_loadu_si128(t)),
+             _mm256_broadcastsi128_si256(_mm_loadu_si128(t.offset(1))),
+             _mm256_broadcastsi128_si256(_mm_loadu_si128(t.offset(2))),
+             _mm256_broadcastsi128_si256(_mm_loadu_si128(t.offset(3)))];
+    // Reverse each 128-bit lane, then swap the lanes.
+    let lanes = _mm256_set_epi8(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+                                0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+    let reverse = |x| {
+        let x = _mm256_shuffle_epi8(x, lanes);
+        _mm256_permute2x128_si256(x, x, 0x01)
+    };
+    let len = cmp::min(left.len(), right.len());
+    let mut i = 0;
+    while i + 32 <= len {
+        let x = left.as_mut_ptr().offset(i as isize) as *mut __m256i;
+        let y = right.as_mut_ptr().offset((right.len() - i - 32) as isize) as *mut __m256i;
+        match (complement_avx2(_mm256_loadu_si256(x), &t),
+               complement_avx2(_mm256_loadu_si256(y), &t)) {
+            (Some(a), Some(b)) => {
+                _mm256_storeu_si256(x, reverse(b));
+                _mm256_storeu_si256(y, reverse(a));
+            }
+            _ => break,
+        }
+        i += 32;
+    }
+    i
+}
 
-            // If we've reached the middle of the sequence and there is an odd
-            // number of bytes remaining, the odd one will now be on the left.
-            if a.len() > b.len() {
-                let mid = a.split_off_right(1);
-                mid[0] = table[mid[0] as usize]
+/// `reverse_chunks` for 16-byte blocks using NEON table lookups.
+///
+/// As with SSSE3, only bytes in 0x40..0x80 are handled; `tbl` can look up all
+/// 64 entries of that part of `table` at once.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn reverse_chunks_neon(left: &mut [u8], right: &mut [u8], table: &[u8; 256]) -> usize {
+    use arch::*;
+    let t = vld1q_u8_x4(table.as_ptr().offset(0x40));
+    let complement = |x| {
+        let index = vsubq_u8(x, vdupq_n_u8(0x40));
+        if vmaxvq_u8(index) < 0x40 { Some(vqtbl4q_u8(t, index)) } else { None }
+    };
+    let reverse = |x| {
+        let x = vrev64q_u8(x);
+        vextq_u8(x, x, 8)
+    };
+    let len = cmp::min(left.len(), right.len());
+    let mut i = 0;
+    while i + 16 <= len {
+        let x = left.as_mut_ptr().offset(i as isize);
+        let y = right.as_mut_ptr().offset((right.len() - i - 16) as isize);
+        match (complement(vld1q_u8(x)), complement(vld1q_u8(y))) {
+            (Some(a), Some(b)) => {
+                vst1q_u8(x, reverse(b));
+                vst1q_u8(y, reverse(a));
             }
+            _ => break,
+        }
+        i += 16;
+    }
+    i
+}
 
-            reverse_chunks(a, b, table);
+/// Compute the reverse complement on chunks from opposite ends of a sequence,
+/// one line at a time.
+///
+/// `left` must start at the beginning of a line. If there are an odd number of
+/// bytes, `right` will initially be 1 byte longer than `left`; otherwise they
+/// will have equal lengths.
+fn reverse_complement_lines(mut left: &mut [u8],
+                            mut right: &mut [u8],
+                            trailing_len: usize,
+                            table: &[u8; 256]) {
+    // Each iteration swaps one line from the start of the sequence with one
+    // from the end.
+    while left.len() > 0  || right.len() > 0 {
+        // Get the chunk up to the newline in `right`.
+        let mut a = left.split_off_left(trailing_len);
+        let mut b = right.split_off_right(trailing_len);
+        right.split_off_right(1); // Skip the newline in `right`.
+
+        // If we've reached the middle of the sequence here and there is an
+        // odd number of bytes remaining, the odd one will be on the right.
+        if b.len() > a.len() {
+            let mid = b.split_off_left(1);
+            mid[0] = table[mid[0] as usize];
         }
+
+        reverse_chunks(a, b, table);
+
+        // Get the chunk up to the newline in `left`.
+        let n = LINE_LEN - 1 - trailing_len;
+        a = left.split_off_left(n);
+        b = right.split_off_right(n);
+        left.split_off_left(1); // Skip the newline in `left`.
+
+        // If we've reached the middle of the sequence and there is an odd
+        // number of bytes remaining, the odd one will now be on the left.
+        if a.len() > b.len() {
+            let mid = a.split_off_right(1);
+            mid[0] = table[mid[0] as usize]
+        }
+
+        reverse_chunks(a, b, table);
+    }
+}
+
+/// Compute the reverse complement on chunks from opposite ends of a sequence,
+/// in parallel across `threads` threads. The requirements are the same as
+/// `reverse_complement_lines`.
+#[cfg(not(feature = "std-threads"))]
+fn reverse_complement_left_right(left: &mut [u8],
+                                 right: &mut [u8],
+                                 trailing_len: usize,
+                                 threads: usize,
+                                 table: &[u8; 256]) {
+    let sequential_size = sequential_size(left.len(), threads);
+    reverse_complement_join(left, right, trailing_len, sequential_size, table);
+}
+
+/// Recursively split chunks until they are no larger than `sequential_size`,
+/// and reverse the pieces in parallel tasks.
+#[cfg(not(feature = "std-threads"))]
+fn reverse_complement_join(mut left: &mut [u8],
+                           mut right: &mut [u8],
+                           trailing_len: usize,
+                           sequential_size: usize,
+                           table: &[u8; 256]) {
+    let len = left.len();
+    if len <= sequential_size {
+        reverse_complement_lines(left, right, trailing_len, table);
     } else {
         // Divide large chunks in half and fork them into two parallel tasks.
         let line_count = len / LINE_LEN;
@@ -131,56 +443,2031 @@ fn reverse_complement_left_right(mut left: &mut [u8],
 
         let left1 = left.split_off_left(mid);
         let right1 = right.split_off_right(mid);
-        rayon::join(|| reverse_complement_left_right(left,  right,  trailing_len, table),
-                    || reverse_complement_left_right(left1, right1, trailing_len, table));
+        rayon::join(
+            || reverse_complement_join(left,  right,  trailing_len, sequential_size, table),
+            || reverse_complement_join(left1, right1, trailing_len, sequential_size, table));
+    }
+}
+
+/// Compute the reverse complement on chunks from opposite ends of a sequence,
+/// in parallel, by giving an equal number of lines from each end to each of
+/// `threads` threads. The requirements are the same as
+/// `reverse_complement_lines`.
+#[cfg(feature = "std-threads")]
+fn reverse_complement_left_right(mut left: &mut [u8],
+                                 mut right: &mut [u8],
+                                 trailing_len: usize,
+                                 threads: usize,
+                                 table: &[u8; 256]) {
+    let per_thread = left.len() / LINE_LEN / threads * LINE_LEN;
+    if threads == 1 || per_thread < MIN_SEQUENTIAL_SIZE {
+        return reverse_complement_lines(left, right, trailing_len, table);
+    }
+    thread::scope(|s| {
+        for _ in 1..threads {
+            let left = left.split_off_left(per_thread);
+            let right = right.split_off_right(per_thread);
+            s.spawn(move || reverse_complement_lines(left, right, trailing_len, table));
+        }
+        // The innermost chunks, including the middle of the sequence.
+        reverse_complement_lines(left, right, trailing_len, table);
+    });
+}
+
+/// The largest chunk to reverse in one task, when `len` bytes from each end
+/// are split across `threads` threads. Aim for a few tasks per thread so work
+/// stealing can even out the load, without going below the size where the
+/// cost of forking outweighs the work.
+#[cfg(not(feature = "std-threads"))]
+fn sequential_size(len: usize, threads: usize) -> usize {
+    cmp::max(MIN_SEQUENTIAL_SIZE, len / (threads * TASKS_PER_THREAD))
+}
+
+/// Stand-in for rayon's thread pool, which only records how many threads to
+/// split each sequence across.
+#[cfg(feature = "std-threads")]
+struct ThreadPool {
+    threads: usize,
+}
+
+#[cfg(feature = "std-threads")]
+impl ThreadPool {
+    fn install<F: FnOnce() -> R, R>(&self, op: F) -> R {
+        op()
+    }
+
+    fn num_threads(&self) -> usize {
+        self.threads
+    }
+}
+
+/// Create a thread pool with `n` threads.
+#[cfg(not(feature = "std-threads"))]
+fn new_pool(n: usize) -> ThreadPool {
+    ThreadPool::new(Configuration::new().set_num_threads(n)).unwrap()
+}
+
+#[cfg(feature = "std-threads")]
+fn new_pool(n: usize) -> ThreadPool {
+    // wasi can't start any more threads.
+    ThreadPool { threads: if cfg!(target_os = "wasi") { 1 } else { n } }
+}
+
+/// Run `op` in `pool`, or in the default pool if there isn't one, passing it
+/// the number of threads available.
+fn in_pool<F, R>(pool: Option<&ThreadPool>, op: F) -> R
+    where F: FnOnce(usize) -> R + Send, R: Send
+{
+    match pool {
+        Some(pool) => {
+            let threads = pool.num_threads();
+            pool.install(|| op(threads))
+        }
+        None => {
+            // Looking this up can take several system calls, so only do it once.
+            static THREADS: OnceLock<usize> = OnceLock::new();
+            let threads = *THREADS.get_or_init(|| {
+                thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+            });
+            op(threads)
+        }
     }
 }
 
 /// Compute the reverse complement of one sequence.
-fn reverse_complement(seq: &mut [u8], table: &[u8; 256]) {
+fn reverse_complement(seq: &mut [u8], threads: usize, table: &[u8; 256]) {
     let len = seq.len();
     let trailing_len = len % LINE_LEN;
     let (left, right) = seq.split_at_mut(len / 2);
-    reverse_complement_left_right(left, right, trailing_len, table);
+    reverse_complement_left_right(left, right, trailing_len, threads, table);
 }
 
-/// Read sequences from stdin and print the reverse complement to stdout.
-fn run() -> io::Result<()> {
-    let stdin = File::open("/dev/stdin")?;
-    let size = stdin.metadata()?.len() as usize;
-    let mut input = BufReader::with_capacity(READ_SIZE, stdin);
+/// Compute the reverse complement of a sequence with no line breaks.
+fn reverse_complement_line(seq: &mut [u8], table: &[u8; 256]) {
+    let len = seq.len();
+    let (left, mut right) = seq.split_at_mut(len / 2);
+    if right.len() > left.len() {
+        let mid = right.split_off_left(1);
+        mid[0] = table[mid[0] as usize];
+    }
+    reverse_chunks(left, right, table);
+}
+
+/// Remove all carriage returns from `buf[start..]`, shifting the remaining
+/// bytes down and truncating `buf` to its new length.
+fn strip_cr(buf: &mut Vec<u8>, start: usize) {
+    let mut end = start;
+    for i in start..buf.len() {
+        let b = buf[i];
+        if b != b'\r' {
+            buf[end] = b;
+            end += 1;
+        }
+    }
+    buf.truncate(end);
+}
+
+/// Open `path` for reading, hinting that it will be read once from start to
+/// end so the OS can prefetch it aggressively. Miri can't make the call, so
+/// it goes without.
+#[cfg(all(target_os = "linux", not(miri)))]
+fn open_sequential(path: &Path) -> io::Result<File> {
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+
+    const POSIX_FADV_SEQUENTIAL: c_int = 2;
+    const POSIX_FADV_WILLNEED: c_int = 3;
+    extern "C" {
+        // glibc only has a 64-bit `off_t` version under this name on 32-bit
+        // targets; musl's `posix_fadvise` always takes a 64-bit offset.
+        #[cfg_attr(not(target_env = "musl"), link_name = "posix_fadvise64")]
+        fn posix_fadvise(fd: c_int, offset: i64, len: i64, advice: c_int) -> c_int;
+    }
+
+    let file = File::open(path)?;
+    // These are only hints, and fail harmlessly on pipes.
+    unsafe {
+        posix_fadvise(file.as_raw_fd(), 0, 0, POSIX_FADV_SEQUENTIAL);
+        posix_fadvise(file.as_raw_fd(), 0, 0, POSIX_FADV_WILLNEED);
+    }
+    Ok(file)
+}
+
+#[cfg(windows)]
+fn open_sequential(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_FLAG_SEQUENTIAL_SCAN: u32 = 0x0800_0000;
+    OpenOptions::new().read(true).custom_flags(FILE_FLAG_SEQUENTIAL_SCAN).open(path)
+}
+
+#[cfg(not(any(all(target_os = "linux", not(miri)), windows)))]
+fn open_sequential(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+/// Open stdin as a `File`, so its size can be looked up and it can be given
+/// the same hints as any other input.
+#[cfg(unix)]
+fn open_stdin(sequential: bool) -> io::Result<File> {
+    let path = Path::new("/dev/stdin");
+    if sequential { open_sequential(path) } else { File::open(path) }
+}
+
+/// Windows has no /dev/stdin, so duplicate the handle instead.
+#[cfg(windows)]
+fn open_stdin(_sequential: bool) -> io::Result<File> {
+    use std::os::windows::io::AsHandle;
+    Ok(File::from(io::stdin().as_handle().try_clone_to_owned()?))
+}
+
+/// wasi has neither /dev/stdin nor a way to duplicate a descriptor, so take
+/// over stdin itself; it's closed when the `File` is dropped, but by then
+/// it's been read to the end.
+#[cfg(target_os = "wasi")]
+fn open_stdin(_sequential: bool) -> io::Result<File> {
+    use std::os::wasi::io::FromRawFd;
+    Ok(unsafe { File::from_raw_fd(0) })
+}
 
-    // Read the input, splitting it into sequences.
-    let mut buf = Vec::with_capacity(size);
+/// The sending end of a queue between the stages of the pipeline.
+#[cfg(not(target_os = "wasi"))]
+type Sender<T> = SyncSender<T>;
+
+/// On wasi the stages run one after another, so the queues have to be able
+/// to hold everything.
+#[cfg(target_os = "wasi")]
+type Sender<T> = std::sync::mpsc::Sender<T>;
+#[cfg(target_os = "wasi")]
+use std::sync::mpsc::channel;
+
+/// Compression formats recognized by their magic numbers.
+#[derive(PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Find out how `input` is compressed, without consuming any of it.
+fn detect_compression<R: BufRead>(input: &mut R) -> io::Result<Compression> {
+    let buf = input.fill_buf()?;
+    Ok(if buf.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip
+    } else if buf.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    })
+}
+
+/// Reads from another reader on a separate thread, so that decompressing the
+/// input overlaps with reversing it.
+#[cfg(not(target_os = "wasi"))]
+struct ThreadReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl ThreadReader {
+    fn new<R: Read + Send + 'static>(mut input: R) -> ThreadReader {
+        let (tx, rx) = sync_channel(4);
+        thread::spawn(move || loop {
+            let mut buf = Vec::with_capacity(CHUNK_SIZE);
+            match (&mut input).take(CHUNK_SIZE as u64).read_to_end(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => if tx.send(Ok(buf)).is_err() { break },
+                Err(e) => { let _ = tx.send(Err(e)); break }
+            }
+        });
+        ThreadReader { rx: rx, buf: vec![], pos: 0 }
+    }
+}
+
+/// Read `input` on a separate thread, where there are threads.
+#[cfg(not(target_os = "wasi"))]
+fn in_background<R: Read + Send + 'static>(input: R) -> Box<dyn BufRead> {
+    Box::new(ThreadReader::new(input))
+}
+
+#[cfg(target_os = "wasi")]
+fn in_background<R: Read + Send + 'static>(input: R) -> Box<dyn BufRead> {
+    Box::new(BufReader::with_capacity(CHUNK_SIZE, input))
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl Read for ThreadReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let buf = self.fill_buf()?;
+            let n = cmp::min(buf.len(), out.len());
+            out[..n].copy_from_slice(&buf[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl BufRead for ThreadReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            // A closed channel means the end of the input.
+            if let Ok(buf) = self.rx.recv() {
+                self.buf = buf?;
+                self.pos = 0;
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+    }
+}
+
+/// Reads a regular file through io_uring, keeping reads into a set of
+/// registered buffers queued ahead of the one being consumed.
+#[cfg(feature = "uring")]
+struct UringReader {
+    ring: IoUring,
+    file: File,
+    bufs: Vec<Vec<u8>>,
+    /// The file offset and length of the last read queued into each buffer.
+    requests: Vec<(u64, usize)>,
+    /// Completed reads that haven't been consumed yet, for each buffer.
+    results: Vec<Option<i32>>,
+    in_flight: usize,
+    next_offset: u64,
+    current: usize,
+    pos: usize,
+    filled: usize,
+    started: bool,
+}
+
+#[cfg(feature = "uring")]
+impl UringReader {
+    /// Read `file` from `offset` onwards, or give it back if io_uring can't be
+    /// set up.
+    fn new(file: File, offset: u64) -> Result<UringReader, File> {
+        let ring = match IoUring::new(URING_DEPTH as u32) {
+            Ok(ring) => ring,
+            Err(_) => return Err(file),
+        };
+        let mut bufs = vec![vec![0; CHUNK_SIZE]; URING_DEPTH];
+        // `IoSliceMut` is guaranteed to have the same layout as `iovec`.
+        let iovecs: Vec<_> = bufs.iter_mut().map(|buf| io::IoSliceMut::new(buf)).collect();
+        let registered = unsafe {
+            let iovecs = std::slice::from_raw_parts(iovecs.as_ptr() as *const _, iovecs.len());
+            ring.submitter().register_buffers(iovecs)
+        };
+        if registered.is_err() {
+            return Err(file);
+        }
+
+        let mut reader = UringReader {
+            ring: ring,
+            file: file,
+            bufs: bufs,
+            requests: vec![(0, 0); URING_DEPTH],
+            results: vec![None; URING_DEPTH],
+            in_flight: 0,
+            next_offset: offset,
+            current: 0,
+            pos: 0,
+            filled: 0,
+            started: false,
+        };
+        for i in 0..URING_DEPTH {
+            let offset = reader.next_offset;
+            reader.next_offset += CHUNK_SIZE as u64;
+            reader.queue(i, offset, CHUNK_SIZE);
+        }
+        Ok(reader)
+    }
+
+    /// Queue a read of `len` bytes at `offset` into the start of buffer `i`,
+    /// to be submitted along with the next wait for a completion.
+    fn queue(&mut self, i: usize, offset: u64, len: usize) {
+        let fd = types::Fd(self.file.as_raw_fd());
+        let entry = opcode::ReadFixed::new(fd, self.bufs[i].as_mut_ptr(), len as u32, i as u16)
+            .offset(offset)
+            .build()
+            .user_data(i as u64);
+        // There is a submission queue entry for each buffer, so this can't fail.
+        unsafe { self.ring.submission().push(&entry).unwrap() };
+        self.requests[i] = (offset, len);
+        self.in_flight += 1;
+    }
+
+    /// Record the completion of any finished reads.
+    fn reap(&mut self) {
+        for cqe in self.ring.completion() {
+            self.results[cqe.user_data() as usize] = Some(cqe.result());
+            self.in_flight -= 1;
+        }
+    }
+
+    /// Block until all queued reads have finished.
+    fn wait_all(&mut self) {
+        while self.in_flight > 0 {
+            if self.ring.submit_and_wait(self.in_flight).is_err() {
+                break
+            }
+            self.reap();
+        }
+    }
+
+    /// Requeue the buffer that was just consumed and move on to the next one.
+    fn next_buffer(&mut self) -> io::Result<()> {
+        if self.started {
+            let (offset, len) = self.requests[self.current];
+            if self.filled < len {
+                // Finish a short read before moving on to later buffers.
+                let current = self.current;
+                self.queue(current, offset + self.filled as u64, len - self.filled);
+            } else {
+                let (current, offset) = (self.current, self.next_offset);
+                self.next_offset += CHUNK_SIZE as u64;
+                self.queue(current, offset, CHUNK_SIZE);
+                self.current = (self.current + 1) % URING_DEPTH;
+            }
+            self.ring.submit()?;
+        }
+        self.started = true;
+        while self.results[self.current].is_none() {
+            self.ring.submit_and_wait(1)?;
+            self.reap();
+        }
+        let result = self.results[self.current].take().unwrap();
+        if result < 0 {
+            return Err(io::Error::from_raw_os_error(-result));
+        }
+        self.filled = result as usize;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "uring")]
+impl Read for UringReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let buf = self.fill_buf()?;
+            let n = cmp::min(buf.len(), out.len());
+            out[..n].copy_from_slice(&buf[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "uring")]
+impl BufRead for UringReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // An empty read means the end of the file.
+        if self.pos == self.filled && !(self.started && self.filled == 0) {
+            self.next_buffer()?;
+        }
+        Ok(&self.bufs[self.current][self.pos..self.filled])
+    }
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+    }
+}
+
+#[cfg(feature = "uring")]
+impl Drop for UringReader {
+    fn drop(&mut self) {
+        // The kernel may still be writing into the buffers.
+        self.wait_all();
+    }
+}
+
+/// Read uncompressed input, through io_uring if it is a regular file.
+#[cfg(feature = "uring")]
+fn uncompressed_input(mut input: BufReader<File>) -> io::Result<Box<dyn BufRead>> {
+    if !input.get_ref().metadata()?.is_file() {
+        return Ok(Box::new(input));
+    }
+    let offset = input.stream_position()?;
+    match UringReader::new(input.into_inner(), offset) {
+        Ok(reader) => Ok(Box::new(reader)),
+        Err(file) => {
+            let mut input = BufReader::with_capacity(READ_SIZE, file);
+            input.seek(SeekFrom::Start(offset))?;
+            Ok(Box::new(input))
+        }
+    }
+}
+
+#[cfg(not(feature = "uring"))]
+fn uncompressed_input(input: BufReader<File>) -> io::Result<Box<dyn BufRead>> {
+    Ok(Box::new(input))
+}
+
+/// Run `f` with the file at `path`, or stdout if there is none, as its output,
+/// compressed with zstd if `zstd` is set. A regular file is first made `size`
+/// bytes long, so the file system can allocate it in one go, and then cut down
+/// to what was written.
+fn with_output<F>(options: &Options, path: Option<&Path>, size: u64, f: F) -> io::Result<()>
+    where F: FnOnce(&mut dyn Write) -> io::Result<()>
+{
+    #[cfg(feature = "progress")]
+    let f = move |output: &mut dyn Write| -> io::Result<()> {
+        if !options.progress {
+            return f(output)
+        }
+        let mut output = ProgressWriter { inner: output, progress: Progress::new(size) };
+        f(&mut output)?;
+        output.progress.finish();
+        Ok(())
+    };
+    let zstd = options.zstd;
+    if options.checksum {
+        let mut checksum = Checksum::new();
+        write_output(zstd, &mut checksum, f)?;
+        return writeln!(io::stdout(), "{:016x}", checksum.finish());
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return write_output(zstd, &mut io::stdout().lock(), f),
+    };
+    let file = File::create(path).map_err(|e| path_error(path, e))?;
+    // Devices and pipes such as /dev/null have no length to set.
+    let regular = file.metadata()?.is_file();
+    if regular {
+        file.set_len(size)?;
+    }
+    let mut output = BufWriter::with_capacity(4 * CHUNK_SIZE, file);
+    write_output(zstd, &mut output, f)?;
+    let mut file = output.into_inner().map_err(|e| e.into_error())?;
+    if !regular {
+        return Ok(());
+    }
+    let len = file.stream_position()?;
+    file.set_len(len)
+}
+
+fn write_output<F>(zstd: bool, output: &mut dyn Write, f: F) -> io::Result<()>
+    where F: FnOnce(&mut dyn Write) -> io::Result<()>
+{
+    if zstd {
+        #[cfg(feature = "zstd")]
+        {
+            let mut encoder = zstd::Encoder::new(&mut *output, 0)?;
+            f(&mut encoder)?;
+            encoder.finish()?;
+            return output.flush();
+        }
+    }
+    f(output)?;
+    output.flush()
+}
+
+include!("common/progress.rs");
+
+/// Passes writes on to `inner`, counting them in `progress`.
+#[cfg(feature = "progress")]
+struct ProgressWriter<W> {
+    inner: W,
+    progress: Progress,
+}
+
+#[cfg(feature = "progress")]
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.progress.add(len);
+        Ok(len)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let len = self.inner.write_vectored(bufs)?;
+        self.progress.add(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+include!("common/checksum.rs");
+
+/// Input for the thread that reverses the records.
+enum Records {
+    /// The start of another input file, for reporting where errors are.
+    Start(PathBuf),
+    /// Some FASTA records, and the range of each one's sequence data.
+    Fasta(Vec<u8>, Vec<Range<usize>>),
+    /// Some FASTQ records, and the ranges of each one's sequence and quality
+    /// lines.
+    Fastq(Vec<u8>, Vec<(Range<usize>, Range<usize>)>),
+}
+
+/// Whether `input` looks like FASTQ rather than FASTA.
+fn is_fastq<R: BufRead>(input: &mut R) -> io::Result<bool> {
+    Ok(input.fill_buf()?.first() == Some(&b'@'))
+}
+
+/// Read FASTA records from `input`, sending them to `tx` as soon as they add up
+/// to `CHUNK_SIZE` bytes, so that small records don't each need a trip through
+/// the queue.
+fn read_fasta_records<R: BufRead>(mut input: R, tx: Sender<Records>) -> io::Result<()> {
+    let mut buf = vec![];
     let mut seqs = vec![];
+    let mut crlf = None;
     loop {
         // Read the header line.
+        let record_start = buf.len();
         input.read_until(b'\n', &mut buf)?;
+        // Files with Windows line endings are detected from the first header,
+        // and have every \r stripped so the output uses plain \n throughout.
+        let crlf = *crlf.get_or_insert_with(|| buf.ends_with(b"\r\n"));
+        if crlf {
+            strip_cr(&mut buf, record_start);
+        }
         let seq_start = buf.len();
-        // Read sequence data.
-        input.read_until(b'>', &mut buf)?;
+        // Read sequence data, up to a '>' at the start of a line.
+        let more = loop {
+            if input.read_until(b'>', &mut buf)? == 0 || buf.last() != Some(&b'>') {
+                break false
+            }
+            if buf[buf.len() - 2] == b'\n' {
+                break true
+            }
+        };
+
+        // The '>' of the following record, if any, belongs in the next buffer.
+        if more {
+            buf.pop();
+        }
+        if crlf {
+            strip_cr(&mut buf, seq_start);
+        }
+        // Exclude the final "\n", if the input doesn't just stop. A record
+        // with an empty body has nothing to reverse, and neither does the
+        // empty input.
+        let seq_end = if buf.ends_with(b"\n") { buf.len() - 1 } else { buf.len() };
+        seqs.push(seq_start..cmp::max(seq_start, seq_end));
+
+        if buf.len() >= CHUNK_SIZE || !more {
+            // Guess that the next batch will be about the same size as this
+            // one.
+            let capacity = buf.len();
+            let records = replace(&mut buf, Vec::with_capacity(capacity));
+            if tx.send(Records::Fasta(records, replace(&mut seqs, vec![]))).is_err() || !more {
+                // Either we reached the end of the input, or a later stage has
+                // failed and will report the error itself.
+                break
+            }
+        }
+        buf.push(b'>');
+    }
+    Ok(())
+}
+
+/// Read FASTA records from `input`, which holds exactly `size` bytes, with a
+/// single read into one buffer, and then send them all to `tx` at once.
+fn read_fasta_whole<R: Read>(mut input: R, mut buf: Vec<u8>, tx: Sender<Records>) -> io::Result<()> {
+    input.read_to_end(&mut buf)?;
+    let header_end = memchr(b'\n', &buf).unwrap_or(buf.len());
+    if buf[..header_end].ends_with(b"\r") {
+        strip_cr(&mut buf, 0);
+    }
 
-        let len = buf.len();
-        if buf[len - 1] == b'>' {
-            // Found the start of a new sequence.
-            seqs.push(seq_start..len - 2); // exclude "\n>"
+    // Each record's header runs to the end of its first line, and its
+    // sequence data runs from there up to the next '>' at the start of a line.
+    let mut seqs = vec![];
+    let mut start = 0;
+    while start < buf.len() {
+        let seq_start = memchr(b'\n', &buf[start..]).map_or(buf.len(), |i| start + i + 1);
+        let end = if buf.get(seq_start) == Some(&b'>') {
+            seq_start
         } else {
-            // Reached the end of the input.
-            seqs.push(seq_start..len - 1); // exclude "\n"
+            memmem::find(&buf[seq_start..], b"\n>").map_or(buf.len(), |i| seq_start + i + 1)
+        };
+        // Exclude the "\n", which the last record may not have.
+        let seq_end = if end > seq_start && buf[end - 1] == b'\n' { end - 1 } else { end };
+        seqs.push(seq_start..seq_end);
+        start = end;
+    }
+    let _ = tx.send(Records::Fasta(buf, seqs));
+    Ok(())
+}
+
+/// Read FASTQ records from `input`, sending them to `tx` in batches of about
+/// `CHUNK_SIZE` bytes. Each record must have its sequence and its quality
+/// scores on a single line each.
+fn read_fastq_records<R: BufRead>(mut input: R, tx: Sender<Records>) -> io::Result<()> {
+    // Read one line into `buf`, returning its range without the line ending.
+    let read_line = |input: &mut R, buf: &mut Vec<u8>| -> io::Result<Range<usize>> {
+        let start = buf.len();
+        input.read_until(b'\n', buf)?;
+        if buf.ends_with(b"\r\n") {
+            buf.pop();
+            *buf.last_mut().unwrap() = b'\n';
+        }
+        let end = if buf.last() == Some(&b'\n') { buf.len() - 1 } else { buf.len() };
+        Ok(start..end)
+    };
+
+    let mut buf = Vec::with_capacity(CHUNK_SIZE + READ_SIZE);
+    let mut records = vec![];
+    for count in 1.. {
+        if read_line(&mut input, &mut buf)?.start == buf.len() {
+            break // Reached the end of the input.
+        }
+        let seq = read_line(&mut input, &mut buf)?;
+        read_line(&mut input, &mut buf)?; // The '+' line.
+        let qual = read_line(&mut input, &mut buf)?;
+        if seq.len() != qual.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("FASTQ record {} has {} bases but {} quality scores",
+                        count, seq.len(), qual.len())));
+        }
+        records.push((seq, qual));
+
+        if buf.len() >= CHUNK_SIZE {
+            let buf = replace(&mut buf, Vec::with_capacity(CHUNK_SIZE + READ_SIZE));
+            if tx.send(Records::Fastq(buf, replace(&mut records, vec![]))).is_err() {
+                return Ok(())
+            }
+        }
+    }
+    if !records.is_empty() {
+        let _ = tx.send(Records::Fastq(buf, records));
+    }
+    Ok(())
+}
+
+/// Find the byte ranges of the header line and of the sequence data of every
+/// record in `input`, both without their final newlines.
+fn scan_records(input: &mut File) -> io::Result<Vec<(Range<u64>, Range<u64>)>> {
+    input.seek(SeekFrom::Start(0))?;
+    let mut input = BufReader::with_capacity(CHUNK_SIZE, input);
+    let mut records = vec![];
+    let mut header_start = None;
+    let mut header_end = None;
+    let mut at_line_start = true;
+    let mut pos = 0;
+    loop {
+        let len = {
+            let buf = input.fill_buf()?;
+            // Visit each line start in turn, checking for a '>', and each
+            // newline, which may end the current header.
+            let mut line_start = if at_line_start { Some(0) } else { None };
+            let mut newlines = memchr_iter(b'\n', buf);
+            loop {
+                if let Some(j) = line_start {
+                    if buf.get(j) == Some(&b'>') {
+                        let i = pos + j as u64;
+                        if let Some(start) = header_start {
+                            let end = header_end.unwrap_or(i);
+                            let seq_start = cmp::min(end + 1, i);
+                            records.push((start..end, seq_start..cmp::max(seq_start, i - 1)));
+                        }
+                        header_start = Some(i);
+                        header_end = None;
+                    }
+                }
+                match newlines.next() {
+                    Some(nl) => {
+                        if header_end.is_none() {
+                            header_end = Some(pos + nl as u64);
+                        }
+                        line_start = Some(nl + 1);
+                    }
+                    None => break,
+                }
+            }
+            if let Some(&b) = buf.last() {
+                at_line_start = b == b'\n';
+            }
+            buf.len()
+        };
+        if len == 0 {
             break
         }
+        input.consume(len);
+        pos += len as u64;
+    }
+    if let Some(start) = header_start {
+        let end = header_end.unwrap_or(pos);
+        let seq_start = cmp::min(end + 1, pos);
+        let seq_end = if at_line_start { pos - 1 } else { pos };
+        records.push((start..end, seq_start..cmp::max(seq_start, seq_end)));
+    }
+    Ok(records)
+}
+
+/// Print the reverse complement of a seekable input without loading it into
+/// memory. Each sequence is read backwards one chunk at a time, complemented,
+/// and re-wrapped into lines of `width` bases.
+fn run_out_of_core(mut input: File,
+                   width: usize,
+                   table: &[u8; 256],
+                   output: &mut dyn Write) -> io::Result<()> {
+    let records = scan_records(&mut input)?;
+    let mut output = BufWriter::with_capacity(CHUNK_SIZE, output);
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut lines = Vec::with_capacity(CHUNK_SIZE + CHUNK_SIZE / cmp::max(width, 1));
+    for (header, seq) in records {
+        let len = usize::try_from(header.end - header.start)
+            .map_err(|_| invalid_input("header too long for this platform".into()))?;
+        let mut buf = vec![0; len];
+        input.seek(SeekFrom::Start(header.start))?;
+        input.read_exact(&mut buf)?;
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+        output.write_all(&buf)?;
+        output.write_all(b"\n")?;
+
+        let mut end = seq.end;
+        let mut col = 0;
+        while end > seq.start {
+            let start = cmp::max(seq.start, end.saturating_sub(CHUNK_SIZE as u64));
+            let chunk = &mut chunk[..(end - start) as usize];
+            input.seek(SeekFrom::Start(start))?;
+            input.read_exact(chunk)?;
+            for &b in chunk.iter().rev() {
+                if b == b'\n' || b == b'\r' {
+                    continue
+                }
+                lines.push(table[b as usize]);
+                col += 1;
+                if col == width {
+                    lines.push(b'\n');
+                    col = 0;
+                }
+            }
+            output.write_all(&lines)?;
+            lines.clear();
+            end = start;
+        }
+        if col != 0 {
+            output.write_all(b"\n")?;
+        }
+    }
+    output.flush()
+}
+
+/// Rewrite the file at `path` with its reverse complement.
+///
+/// With the default line wrapping, each sequence keeps its length and its
+/// position in the file, so it can be reversed by swapping chunks of whole
+/// lines from both of its ends without making a second copy of the file.
+fn run_in_place(path: &Path,
+                reverse: bool,
+                table: &[u8; 256],
+                pool: Option<&ThreadPool>) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)
+        .map_err(|e| path_error(path, e))?;
+    {
+        let mut input = BufReader::new(&file);
+        if detect_compression(&mut input)? != Compression::None {
+            return Err(invalid_input("can't rewrite a compressed file in place".into()));
+        }
+        if is_fastq(&mut input)? {
+            return Err(invalid_input("--in-place needs FASTA input".into()));
+        }
+    }
+    let records = scan_records(&mut file)?;
+    if let Some(&(ref header, _)) = records.first() {
+        let mut last = [0];
+        if header.end > header.start {
+            file.seek(SeekFrom::Start(header.end - 1))?;
+            file.read_exact(&mut last)?;
+        }
+        if last[0] == b'\r' {
+            return Err(invalid_input("can't rewrite a file with CRLF line endings in place".into()));
+        }
+    }
+
+    let chunk = CHUNK_SIZE / LINE_LEN * LINE_LEN;
+    let mut buf = vec![0; 4 * chunk];
+    // Reversing swaps whole lines from the ends, so every sequence has to be
+    // wrapped at `LINE_LEN - 1` bases already. Check them all before anything
+    // is written, rather than leave the file half rewritten.
+    if reverse {
+        for (i, &(_, ref seq)) in records.iter().enumerate() {
+            if !is_wrapped_in_file(&mut file, seq.clone(), &mut buf[..chunk])? {
+                return Err(invalid_input(format!(
+                    "can't reverse record {} in place, as it isn't wrapped at {} bases a line",
+                    i + 1, LINE_LEN - 1)));
+            }
+        }
+    }
+    for (_, seq) in records {
+        let (mut start, mut end) = (seq.start, seq.end);
+        if !reverse {
+            while start < end {
+                let buf = &mut buf[..cmp::min(end - start, chunk as u64) as usize];
+                file.seek(SeekFrom::Start(start))?;
+                file.read_exact(buf)?;
+                complement(buf, table);
+                file.seek(SeekFrom::Start(start))?;
+                file.write_all(buf)?;
+                start += buf.len() as u64;
+            }
+            continue
+        }
+
+        let trailing_len = ((end - start) % LINE_LEN as u64) as usize;
+        // Swap chunks from the ends while there are at least two on each side.
+        while end - start >= 4 * chunk as u64 {
+            let (left, right) = buf.split_at_mut(chunk);
+            let right = &mut right[..chunk];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(left)?;
+            file.seek(SeekFrom::Start(end - chunk as u64))?;
+            file.read_exact(right)?;
+            in_pool(pool, |threads| {
+                reverse_complement_left_right(left, right, trailing_len, threads, table)
+            });
+            file.seek(SeekFrom::Start(start))?;
+            file.write_all(left)?;
+            file.seek(SeekFrom::Start(end - chunk as u64))?;
+            file.write_all(right)?;
+            start += chunk as u64;
+            end -= chunk as u64;
+        }
+        // Then reverse what is left in the middle all at once.
+        let middle = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(middle)?;
+        let len = middle.len();
+        let (left, right) = middle.split_at_mut(len / 2);
+        in_pool(pool, |threads| {
+            reverse_complement_left_right(left, right, trailing_len, threads, table)
+        });
+        file.seek(SeekFrom::Start(start))?;
+        file.write_all(left)?;
+        file.write_all(right)?;
+    }
+    file.sync_all()
+}
+
+/// `is_wrapped` for the sequence at `seq` in `file`, reading it a piece at a
+/// time into `buf`, whose length must be a multiple of `LINE_LEN`.
+fn is_wrapped_in_file(file: &mut File, seq: Range<u64>, buf: &mut [u8]) -> io::Result<bool> {
+    let mut start = seq.start;
+    file.seek(SeekFrom::Start(start))?;
+    while seq.end - start > buf.len() as u64 {
+        file.read_exact(buf)?;
+        // A whole number of lines, the last of them ending in a newline.
+        if !is_wrapped(&buf[..buf.len() - 1]) || buf[buf.len() - 1] != b'\n' {
+            return Ok(false)
+        }
+        start += buf.len() as u64;
+    }
+    let rest = &mut buf[..(seq.end - start) as usize];
+    file.read_exact(rest)?;
+    Ok(is_wrapped(rest))
+}
+
+/// How to lay out the lines of each reversed sequence.
+#[derive(Clone, Copy, PartialEq)]
+enum Wrap {
+    /// Wrap into lines of this many bases, or not at all if it is zero.
+    Width(usize),
+    /// Keep the lengths of the input lines, in their original order.
+    Preserve,
+}
+
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: reverse_complement [OPTIONS] [FILE]...
+
+Print the reverse complement of each FASTA or FASTQ record in the FILEs, or on
+stdin if there are none (or for \"-\"), with FASTA sequences wrapped at 60 bases
+a line.
+
+  --output FILE      write to FILE rather than stdout
+  --separate         write the output for each FILE to FILE.rc
+  --in-place FILE    rewrite FILE with its reverse complement, if its sequences
+                     are already wrapped at 60 bases a line
+  --out-of-core      read a FILE too big to hold in memory in pieces
+  --zstd             compress the output with zstd (needs the zstd feature)
+  --checksum         print a hash of the output rather than the output
+  --verify           check each sequence against a simple implementation, and
+                     show where they differ in hex if they do
+  --strict           reject lines that aren't 60 bases long and bases that
+                     aren't IUPAC codes, rather than working around them
+  --stats            print the length and base counts of each sequence to stderr
+  --progress         show how much has been written so far on stderr (needs the
+                     progress feature)
+  --filter REGEX     only print the records whose headers match REGEX
+  --keep-others      print the records --filter doesn't match unchanged
+  --threads N        use N threads rather than one for each CPU
+  --no-reverse       only complement the bases
+  --no-complement    only reverse them
+  --rna              complement A to U rather than T
+  --preserve-case    complement lowercase bases to lowercase
+  --upper, --lower   make every letter upper or lower case
+  --wrap N           wrap sequences at N bases a line, or not at all for 0
+  --wrap preserve    keep the lengths of the input's lines
+  -h, --help         print this message
+  --version          print how this was built
+";
+
+/// Command-line options.
+struct Options {
+    /// Print `USAGE` and do nothing else.
+    help: bool,
+    /// Print the name and `BUILD_INFO` and do nothing else.
+    version: bool,
+    /// Files to read, one after another, or stdin if there are none.
+    inputs: Vec<PathBuf>,
+    /// Write the output for each input to its own file, named after the input.
+    separate: bool,
+    /// Write the output to this file instead of stdout.
+    output: Option<PathBuf>,
+    /// Read each sequence from the end of the file back, rather than holding
+    /// the whole input in memory.
+    out_of_core: bool,
+    /// Rewrite this file instead of reading stdin and writing stdout.
+    in_place: Option<PathBuf>,
+    /// Compress the output with zstd.
+    zstd: bool,
+    /// Show how much of the output has been written on stderr.
+    progress: bool,
+    /// Print a hash of the output instead of the output itself.
+    checksum: bool,
+    /// Check every sequence against a simple single-threaded implementation.
+    verify: bool,
+    /// Reject input that isn't wrapped at `LINE_LEN` or has bases that aren't
+    /// IUPAC codes, rather than working around it.
+    strict: bool,
+    /// Print the length and base counts of each sequence to stderr.
+    stats: bool,
+    /// Only reverse and print the records whose headers match this.
+    filter: Option<Regex>,
+    /// Print the records that don't match `filter` unchanged.
+    keep_others: bool,
+    /// Size of the thread pool, if not the number of CPUs.
+    threads: Option<usize>,
+    /// If false, sequences are only complemented, keeping their orientation.
+    reverse: bool,
+    /// If false, sequences are only reversed, keeping their original bases.
+    complement: bool,
+    /// Complement adenine to uracil rather than thymine.
+    rna: bool,
+    /// How to choose the case of each base of the output.
+    case: Case,
+    /// If not given, sequences already wrapped at `LINE_LEN` are reversed in
+    /// place, and any others are rewrapped to it.
+    wrap: Option<Wrap>,
+}
+
+impl Options {
+    /// The options when none are given.
+    fn new() -> Options {
+        Options {
+            help: false,
+            version: false,
+            inputs: vec![],
+            separate: false,
+            output: None,
+            out_of_core: false,
+            in_place: None,
+            zstd: false,
+            progress: false,
+            checksum: false,
+            verify: false,
+            strict: false,
+            stats: false,
+            filter: None,
+            keep_others: false,
+            threads: std::env::var("RAYON_NUM_THREADS").ok()
+                .and_then(|n| n.parse().ok())
+                .filter(|&n| n > 0),
+            reverse: true,
+            complement: true,
+            rna: false,
+            case: Case::Normal,
+            wrap: None,
+        }
+    }
+
+    fn from_args() -> io::Result<Options> {
+        let mut options = Options::new();
+        let mut args = std::env::args_os().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("-h") | Some("--help") => options.help = true,
+                Some("--version") => options.version = true,
+                Some("--progress") => options.progress = true,
+                // The input must be a regular file rather than a pipe for this.
+                Some("--out-of-core") => options.out_of_core = true,
+                Some("--in-place") => {
+                    let path = args.next().ok_or_else(|| {
+                        invalid_input("--in-place needs a file name".into())
+                    })?;
+                    options.in_place = Some(path.into());
+                }
+                Some("--no-reverse") => options.reverse = false,
+                Some("--no-complement") => options.complement = false,
+                Some("--zstd") if cfg!(feature = "zstd") => options.zstd = true,
+                Some("--zstd") => return Err(invalid_input("--zstd needs the zstd feature".into())),
+                Some("--verify") => options.verify = true,
+                Some("--checksum") => options.checksum = true,
+                Some("--strict") => options.strict = true,
+                Some("--stats") => options.stats = true,
+                Some("--filter") => {
+                    let re = args.next().ok_or_else(|| {
+                        invalid_input("--filter needs a regular expression".into())
+                    })?;
+                    let re = re.to_str().ok_or_else(|| {
+                        invalid_input(format!("invalid --filter: {:?}", re))
+                    })?;
+                    options.filter = Some(Regex::new(re).map_err(|e| {
+                        invalid_input(format!("invalid --filter: {}", e))
+                    })?);
+                }
+                Some("--keep-others") => options.keep_others = true,
+                Some("--threads") => {
+                    let n = args.next().as_ref().and_then(|s| s.to_str())
+                        .and_then(|n| n.parse().ok())
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| invalid_input("--threads needs a positive number".into()))?;
+                    options.threads = Some(n);
+                }
+                Some("--rna") => options.rna = true,
+                Some("--preserve-case") => options.case = Case::Preserve,
+                Some("--upper") => options.case = Case::Upper,
+                Some("--lower") => options.case = Case::Lower,
+                Some("--wrap") => {
+                    options.wrap = Some(match args.next().as_ref().and_then(|s| s.to_str()) {
+                        Some("preserve") => Wrap::Preserve,
+                        Some(n) => Wrap::Width(n.parse().map_err(|_| {
+                            invalid_input(format!("invalid --wrap width: {}", n))
+                        })?),
+                        None => return Err(invalid_input("--wrap needs a value".into())),
+                    })
+                }
+                Some("--separate") => options.separate = true,
+                Some("--output") => {
+                    let path = args.next().ok_or_else(|| {
+                        invalid_input("--output needs a file name".into())
+                    })?;
+                    options.output = Some(path.into());
+                }
+                Some(s) if s.starts_with("--") => {
+                    return Err(invalid_input(format!("unknown argument {:?}; see --help", arg)));
+                }
+                // "-" is stdin, as usual.
+                _ => options.inputs.push(arg.into()),
+            }
+        }
+        if options.out_of_core && options.wrap == Some(Wrap::Preserve) {
+            return Err(invalid_input("--wrap preserve needs the whole input in memory".into()));
+        }
+        if options.out_of_core && !options.reverse {
+            return Err(invalid_input("--no-reverse can't be used with --out-of-core".into()));
+        }
+        if options.in_place.is_some() &&
+           (options.out_of_core || options.wrap.is_some() || options.zstd) {
+            return Err(invalid_input("--in-place can't be used with --out-of-core, --wrap or \
+                                      --zstd".into()));
+        }
+        if options.filter.is_some() && (options.in_place.is_some() || options.out_of_core) {
+            return Err(invalid_input("--filter can't be used with --in-place or --out-of-core"
+                                     .into()));
+        }
+        if options.stats && (options.in_place.is_some() || options.out_of_core) {
+            return Err(invalid_input("--stats can't be used with --in-place or --out-of-core"
+                                     .into()));
+        }
+        if options.strict && (options.in_place.is_some() || options.out_of_core) {
+            return Err(invalid_input("--strict can't be used with --in-place or --out-of-core"
+                                     .into()));
+        }
+        if options.progress && !cfg!(feature = "progress") {
+            return Err(invalid_input("--progress needs the progress feature".into()));
+        }
+        if options.progress && options.in_place.is_some() {
+            return Err(invalid_input("--progress can't be used with --in-place".into()));
+        }
+        if options.in_place.is_some() && !options.inputs.is_empty() {
+            return Err(invalid_input("--in-place can't be used with other input files".into()));
+        }
+        if options.output.is_some() && (options.separate || options.in_place.is_some()) {
+            return Err(invalid_input("--output can't be used with --separate or --in-place"
+                                     .into()));
+        }
+        if options.checksum &&
+           (options.output.is_some() || options.separate || options.in_place.is_some()) {
+            return Err(invalid_input("--checksum can't be used with --output, --separate or \
+                                      --in-place".into()));
+        }
+        if options.separate && options.inputs.is_empty() {
+            return Err(invalid_input("--separate needs input files".into()));
+        }
+        Ok(options)
+    }
+}
+
+include!("common/path_error.rs");
+
+/// Complement each byte of a sequence in place, without reversing it.
+fn complement(seq: &mut [u8], table: &[u8; 256]) {
+    for x in seq {
+        *x = table[*x as usize];
+    }
+}
+
+/// Compute the reverse complement of `seq` (or just the complement, if not
+/// `reverse`) into `out`, ignoring the line breaks in `seq` and laying out the
+/// result according to `wrap`.
+fn reverse_complement_wrapped(seq: &[u8],
+                              wrap: Wrap,
+                              reverse: bool,
+                              table: &[u8; 256],
+                              out: &mut Vec<u8>) {
+    let mut bases: Vec<u8> = seq.iter()
+        .filter(|&&b| b != b'\n')
+        .map(|&b| table[b as usize])
+        .collect();
+    if reverse {
+        bases.reverse();
+    }
+    let mut rest = &bases[..];
+    let mut line = |out: &mut Vec<u8>, n: usize| {
+        let (a, b) = rest.split_at(cmp::min(n, rest.len()));
+        out.extend_from_slice(a);
+        rest = b;
+    };
+    match wrap {
+        Wrap::Width(0) => line(out, bases.len()),
+        Wrap::Width(n) => {
+            for i in 0..(bases.len() + n - 1) / n {
+                if i > 0 {
+                    out.push(b'\n');
+                }
+                line(out, n);
+            }
+        }
+        Wrap::Preserve => {
+            for (i, l) in seq.split(|&b| b == b'\n').enumerate() {
+                if i > 0 {
+                    out.push(b'\n');
+                }
+                line(out, l.len());
+            }
+        }
+    }
+}
+
+/// Check the result of reversing one sequence in place against the simple
+/// implementation in `reverse_complement_wrapped`.
+fn verify(output: &[u8],
+          input: &[u8],
+          wrap: Wrap,
+          reverse: bool,
+          table: &[u8; 256],
+          n: usize) -> io::Result<()> {
+    let mut expected = Vec::with_capacity(input.len());
+    reverse_complement_wrapped(input, wrap, reverse, table, &mut expected);
+    if output == &expected[..] {
+        return Ok(())
+    }
+    let pos = output.iter().zip(&expected).position(|(a, b)| a != b)
+        .unwrap_or(cmp::min(output.len(), expected.len()));
+    dump_difference(&expected, output, pos)?;
+    Err(io::Error::new(io::ErrorKind::InvalidData,
+                       format!("verification failed for sequence {} at byte {}", n, pos)))
+}
+
+/// How many bytes `dump_difference` shows to a row.
+const DUMP_ROW: usize = 16;
+
+/// Print the rows of `expected` and `output` around `pos`, the first byte where
+/// they differ, on stderr, in hex and ASCII one above the other so that a line
+/// break in the wrong place lines up with the right one. The bytes that differ
+/// are coloured, unless stderr isn't a terminal or NO_COLOR is set.
+fn dump_difference(expected: &[u8], output: &[u8], pos: usize) -> io::Result<()> {
+    let color = io::stderr().is_terminal() &&
+        std::env::var_os("NO_COLOR").map_or(true, |v| v.is_empty());
+    let rows = (cmp::max(expected.len(), output.len()) + DUMP_ROW - 1) / DUMP_ROW;
+    let stderr = io::stderr();
+    let mut err = stderr.lock();
+    writeln!(err, "{:8}   expected, then what was written:", "")?;
+    for row in (pos / DUMP_ROW).saturating_sub(1)..cmp::min(pos / DUMP_ROW + 3, rows) {
+        let start = row * DUMP_ROW;
+        let want = &expected[cmp::min(start, expected.len())..];
+        let got = &output[cmp::min(start, output.len())..];
+        write!(err, "{:08x} - ", start)?;
+        dump_row(&mut err, want, got, if color { "\x1b[31m" } else { "" })?;
+        write!(err, "{:8} + ", "")?;
+        dump_row(&mut err, got, want, if color { "\x1b[32m" } else { "" })?;
+    }
+    Ok(())
+}
+
+/// Print the first `DUMP_ROW` bytes of `this` as one row of `dump_difference`,
+/// starting those that aren't the same in `other` with `highlight`.
+fn dump_row(err: &mut dyn Write, this: &[u8], other: &[u8], highlight: &str) -> io::Result<()> {
+    let mut ascii = String::new();
+    for i in 0..DUMP_ROW {
+        let (on, off) = if !highlight.is_empty() && this.get(i) != other.get(i) {
+            (highlight, "\x1b[0m")
+        } else {
+            ("", "")
+        };
+        match this.get(i) {
+            Some(&b) => {
+                write!(err, "{}{:02x}{} ", on, b, off)?;
+                let c = if b == b' ' || b.is_ascii_graphic() { b as char } else { '.' };
+                ascii.push_str(&format!("{}{}{}", on, c, off));
+            }
+            None => write!(err, "   ")?,
+        }
+    }
+    writeln!(err, " |{}|", ascii)
+}
+
+/// Whether `seq` has line breaks where `reverse_complement` expects them. This
+/// only looks at the end of each line, so it's cheap enough to do for every
+/// sequence, but misses extra line breaks that happen to cancel out.
+fn is_wrapped(seq: &[u8]) -> bool {
+    let lines = seq.len() / LINE_LEN;
+    (seq.is_empty() || seq.len() % LINE_LEN != 0) &&
+        (1..=lines).all(|i| seq[i * LINE_LEN - 1] == b'\n') &&
+        memchr(b'\n', &seq[lines * LINE_LEN..]).is_none()
+}
+
+/// Find the first line of `seq` that doesn't fit the layout `reverse_complement`
+/// relies on: every line `LINE_LEN - 1` bases long, except for a last line that
+/// may be shorter but not empty.
+fn misaligned_line(seq: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    let mut lines = 0;
+    for end in memchr_iter(b'\n', seq) {
+        if end - start != LINE_LEN - 1 {
+            return Some(lines)
+        }
+        start = end + 1;
+        lines += 1;
+    }
+    if seq.len() - start >= LINE_LEN || (start > 0 && start == seq.len()) {
+        Some(lines)
+    } else {
+        None
+    }
+}
+
+/// Find the line and the value of the first byte of `seq` that `valid` doesn't
+/// allow.
+fn invalid_base(seq: &[u8], valid: &[bool; 256]) -> Option<(usize, u8)> {
+    let pos = seq.iter().position(|&b| !valid[b as usize])?;
+    Some((memchr_iter(b'\n', &seq[..pos]).count(), seq[pos]))
+}
+
+/// Reverse each batch of records received from `rx`, and send the pieces of
+/// its output to `tx` for the writer thread.
+fn reverse_records(rx: Receiver<Records>,
+                   options: &Options,
+                   table: &[u8; 256],
+                   pool: Option<&ThreadPool>,
+                   tx: Sender<Batch>) -> io::Result<()> {
+    let mut original = vec![];
+    let mut count = 0;
+    let mut stats = if options.stats { Some(Stats::start()?) } else { None };
+    // Where we are in the input, for `--strict` errors.
+    let mut file = PathBuf::new();
+    let mut line = 1;
+    let mut valid = [false; 256];
+    for &(b, _) in &IUPAC_COMPLEMENTS {
+        valid[b as usize] = true;
+        valid[b.to_ascii_lowercase() as usize] = true;
+    }
+    valid[b'\n' as usize] = true;
+    for records in rx {
+        let (mut buf, seqs) = match records {
+            Records::Start(path) => {
+                file = path;
+                line = 1;
+                continue
+            }
+            Records::Fasta(buf, seqs) => {
+                (buf, seqs.into_iter().map(|seq| (seq, None)).collect::<Vec<_>>())
+            }
+            Records::Fastq(buf, records) => {
+                (buf, records.into_iter().map(|(seq, qual)| (seq, Some(qual))).collect())
+            }
+        };
+
+        // Each record runs from the start of its header to the start of the
+        // next one's.
+        let starts: Vec<usize> = seqs.iter().enumerate().map(|(i, &(ref seq, _))| {
+            if i == 0 { 0 } else { header_range(&buf, seq.start).start }
+        }).collect();
+        let mut parts = vec![];
+        let mut wrapped = vec![];
+        for (i, (seq, qual)) in seqs.into_iter().enumerate() {
+            let record = starts[i]..starts.get(i + 1).cloned().unwrap_or(buf.len());
+            count += 1;
+            let record_line = line;
+            if options.strict {
+                line += memchr_iter(b'\n', &buf[record.clone()]).count();
+            }
+            if let Some(ref filter) = options.filter {
+                let header = &buf[header_range(&buf, seq.start)];
+                if !filter.is_match(header.get(1..).unwrap_or(b"")) {
+                    if options.keep_others {
+                        parts.push(Part::Input(record));
+                    }
+                    continue
+                }
+            }
+            // Input that isn't wrapped at `LINE_LEN` can't be reversed in
+            // place, so it's rewrapped instead, unless `--strict` rejects it.
+            if options.strict {
+                let seq_line = record_line + memchr_iter(b'\n', &buf[record.start..seq.start]).count();
+                let error = if let Some((i, b)) = invalid_base(&buf[seq.clone()], &valid) {
+                    Some((i, format!("invalid base {:?}", char::from(b))))
+                } else if qual.is_none() {
+                    misaligned_line(&buf[seq.clone()]).map(|i| {
+                        (i, format!("line isn't {} bases long", LINE_LEN - 1))
+                    })
+                } else {
+                    None
+                };
+                if let Some((i, msg)) = error {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("{}:{}: {}", file.display(), seq_line + i,
+                                                      msg)));
+                }
+            }
+            let rewrap = options.wrap.or_else(|| {
+                if options.reverse && qual.is_none() && !is_wrapped(&buf[seq.clone()]) {
+                    Some(Wrap::Width(LINE_LEN - 1))
+                } else {
+                    None
+                }
+            });
+            if let Some(ref mut stats) = stats {
+                stats.report(&buf, seq.clone())?;
+            }
+            if options.verify {
+                original.clear();
+                original.extend_from_slice(&buf[seq.clone()]);
+            }
+
+            let wrap = if let Some(qual) = qual {
+                // FASTQ sequences are always on a single line.
+                if options.reverse {
+                    reverse_complement_line(&mut buf[seq.clone()], table);
+                    buf[qual].reverse();
+                } else {
+                    complement(&mut buf[seq.clone()], table);
+                }
+                Wrap::Width(0)
+            } else if let Some(wrap) = rewrap {
+                // Rewrapped sequences are about as long as they were, so the
+                // rest of the batch is room for all of them, and it's only
+                // touched as it's used.
+                if wrapped.capacity() == 0 {
+                    wrapped.reserve(buf.len() - record.start);
+                }
+                let start = wrapped.len();
+                reverse_complement_wrapped(&buf[seq.clone()], wrap, options.reverse, table,
+                                           &mut wrapped);
+                parts.push(Part::Input(record.start..seq.start));
+                parts.push(Part::Wrapped(start..wrapped.len()));
+                parts.push(Part::Input(seq.end..record.end));
+                continue
+            } else if options.reverse {
+                in_pool(pool, |threads| reverse_complement(&mut buf[seq.clone()], threads, table));
+                Wrap::Width(LINE_LEN - 1)
+            } else {
+                complement(&mut buf[seq.clone()], table);
+                Wrap::Preserve
+            };
+            if options.verify {
+                verify(&buf[seq], &original, wrap, options.reverse, table, count)?;
+            }
+            parts.push(Part::Input(record));
+        }
+        if tx.send(Batch { buf, wrapped, parts }).is_err() {
+            // The writer failed, and will report why.
+            return Ok(())
+        }
+    }
+    match stats {
+        Some(stats) => stats.finish(),
+        None => Ok(()),
+    }
+}
+
+/// Write each batch of output received from `rx` to `output`, in order.
+fn write_batches(rx: Receiver<Batch>, output: &mut dyn Write) -> io::Result<()> {
+    let mut pending = Pending::new();
+    loop {
+        // Gather up whatever batches are ready, and write them all at once
+        // when we would otherwise have to wait for more.
+        let batch = match rx.try_recv() {
+            Ok(batch) => batch,
+            Err(TryRecvError::Empty) => {
+                pending.write_to(output)?;
+                match rx.recv() {
+                    Ok(batch) => batch,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        pending.make_room(output)?;
+        let input = pending.add(batch.buf);
+        let wrapped = pending.add(batch.wrapped);
+        for part in batch.parts {
+            match part {
+                Part::Input(range) => pending.part(input, range),
+                Part::Wrapped(range) => pending.part(wrapped, range),
+            }
+        }
+    }
+    pending.write_to(output)
+}
+
+/// The output for a batch of records.
+struct Batch {
+    /// The input buffer, with its sequences reversed in place.
+    buf: Vec<u8>,
+    /// The sequences that were rewrapped instead, one after another.
+    wrapped: Vec<u8>,
+    /// What to write, in order.
+    parts: Vec<Part>,
+}
+
+/// A piece of the output for a batch of records.
+enum Part {
+    /// A range of the input buffer, as reversed in place.
+    Input(Range<usize>),
+    /// A range of the batch's rewrapped sequences.
+    Wrapped(Range<usize>),
+}
+
+/// The range of the header line before the sequence at `seq_start` in `buf`,
+/// without its newline.
+fn header_range(buf: &[u8], seq_start: usize) -> Range<usize> {
+    let end = if seq_start > 0 && buf[seq_start - 1] == b'\n' { seq_start - 1 } else { seq_start };
+    let start = memrchr(b'\n', &buf[..end]).map_or(0, |i| i + 1);
+    start..end
+}
+
+/// Output waiting to be written together with one vectored write.
+struct Pending {
+    bufs: Vec<Vec<u8>>,
+    /// The ranges of `bufs` to write, in order.
+    parts: Vec<(usize, Range<usize>)>,
+    len: usize,
+}
+
+impl Pending {
+    /// Most parts to gather before writing, matching the usual `IOV_MAX`.
+    const MAX_PARTS: usize = 1024;
+
+    fn new() -> Pending {
+        Pending { bufs: vec![], parts: vec![], len: 0 }
+    }
+
+    /// Write everything out first if there is already a lot waiting.
+    fn make_room(&mut self, output: &mut dyn Write) -> io::Result<()> {
+        if self.parts.len() >= Pending::MAX_PARTS || self.len >= 4 * CHUNK_SIZE {
+            self.write_to(output)?;
+        }
+        Ok(())
+    }
+
+    /// Hold on to `buf` until the next write, returning its index for `part`.
+    fn add(&mut self, buf: Vec<u8>) -> usize {
+        self.bufs.push(buf);
+        self.bufs.len() - 1
+    }
+
+    /// Write `range` of the buffer at index `i` next.
+    fn part(&mut self, i: usize, range: Range<usize>) {
+        self.len += range.len();
+        if let Some(&mut (j, ref mut last)) = self.parts.last_mut() {
+            if j == i && last.end == range.start {
+                last.end = range.end;
+                return
+            }
+        }
+        self.parts.push((i, range));
+    }
+
+    /// Write out all the pending output.
+    fn write_to(&mut self, output: &mut dyn Write) -> io::Result<()> {
+        {
+            let bufs = &self.bufs;
+            let mut slices: Vec<_> = self.parts.iter()
+                .map(|&(i, ref range)| IoSlice::new(&bufs[i][range.clone()]))
+                .collect();
+            let mut slices = &mut slices[..];
+            IoSlice::advance_slices(&mut slices, 0);
+            while !slices.is_empty() {
+                match output.write_vectored(slices) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => IoSlice::advance_slices(&mut slices, n),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        self.bufs.clear();
+        self.parts.clear();
+        self.len = 0;
+        Ok(())
+    }
+}
+
+/// Counts of each byte in some sequence data, for `--stats`.
+struct Stats {
+    counts: [u64; 256],
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats { counts: [0; 256] }
+    }
+
+    /// Print the heading for the table of statistics, and start a total.
+    fn start() -> io::Result<Stats> {
+        writeln!(io::stderr(), "record\tbases\tlines\tA\tC\tG\tT\tN\tother")?;
+        Ok(Stats::new())
+    }
+
+    /// Print the statistics for the sequence at `seq` in `buf`, before it is
+    /// reversed, and add them to the total.
+    fn report(&mut self, buf: &[u8], seq: Range<usize>) -> io::Result<()> {
+        let mut stats = Stats::new();
+        for &b in &buf[seq.clone()] {
+            stats.counts[b as usize] += 1;
+        }
+        // The sequence doesn't include its final newline.
+        let lines = if seq.len() > 0 { 1 } else { 0 };
+        stats.counts[b'\n' as usize] += lines;
+
+        // Name the record by its header, without the '>' or '@'.
+        let header = &buf[header_range(buf, seq.start)];
+        stats.print(header.get(1..).unwrap_or(b""))?;
+        for (total, count) in self.counts.iter_mut().zip(stats.counts.iter()) {
+            *total += count;
+        }
+        Ok(())
+    }
+
+    /// Print the total.
+    fn finish(self) -> io::Result<()> {
+        self.print(b"total")
+    }
+
+    fn print(&self, name: &[u8]) -> io::Result<()> {
+        let count = |b: u8| self.counts[b as usize] + self.counts[b.to_ascii_lowercase() as usize];
+        let lines = self.counts[b'\n' as usize];
+        let bases = self.counts.iter().sum::<u64>() - lines;
+        let (a, c, g, t, n) = (count(b'A'), count(b'C'), count(b'G'), count(b'T'), count(b'N'));
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        stderr.write_all(name)?;
+        writeln!(stderr, "\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                 bases, lines, a, c, g, t, n, bases - (a + c + g + t + n))
+    }
+}
+
+/// Open the input file at `path`, where "-" means stdin.
+fn open_input(path: &Path, options: &Options) -> io::Result<BufReader<File>> {
+    // Out-of-core mode reads each sequence backwards, so don't ask for
+    // readahead there.
+    let sequential = !options.out_of_core;
+    let file = if path == Path::new("-") {
+        open_stdin(sequential)?
+    } else if sequential {
+        open_sequential(path).map_err(|e| path_error(path, e))?
+    } else {
+        File::open(path).map_err(|e| path_error(path, e))?
+    };
+    Ok(BufReader::with_capacity(READ_SIZE, file))
+}
+
+/// Read the records from the file at `path`, and send them to `tx`.
+fn read_records(path: &Path, options: &Options, tx: Sender<Records>) -> io::Result<()> {
+    let mut input = open_input(path, options)?;
+    let size = input.get_ref().metadata()?.len();
+    let compression = detect_compression(&mut input)?;
+    let mut input: Box<dyn BufRead> = match compression {
+        Compression::None => uncompressed_input(input)?,
+        Compression::Gzip => in_background(MultiGzDecoder::new(input)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => in_background(zstd::Decoder::with_buffer(input)?),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => {
+            return Err(invalid_input("zstd input needs the zstd feature".into()));
+        }
+    };
+    if tx.send(Records::Start(path.to_owned())).is_err() {
+        return Ok(())
+    }
+    if is_fastq(&mut input)? {
+        read_fastq_records(input, tx)
+    } else if compression != Compression::None {
+        read_fasta_records(input, tx)
+    } else if let Some(buf) = whole_file_buffer(size) {
+        // A regular file can be read all at once.
+        read_fasta_whole(input, buf, tx)
+    } else {
+        read_fasta_records(input, tx)
     }
+}
+
+/// An empty buffer with room for a whole file of `size` bytes, or `None` if
+/// the size is unknown or the buffer can't be had, as for a file over 4 GiB
+/// on a 32-bit target. Such files are read one record at a time instead.
+fn whole_file_buffer(size: u64) -> Option<Vec<u8>> {
+    let size = usize::try_from(size).ok().filter(|&size| size > 0)?;
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(size).ok()?;
+    Some(buf)
+}
 
-    // Compute the reverse complements of each sequence.
-    let table = build_table();
-    for seq in seqs {
-        reverse_complement(&mut buf[seq], &table);
+/// Reverse-complement the concatenation of the files at `inputs`, and write
+/// it to the file at `output`, or stdout if there is none.
+fn run_files(inputs: &[PathBuf],
+             output: Option<&Path>,
+             options: &Options,
+             table: &[u8; 256],
+             pool: Option<&ThreadPool>) -> io::Result<()> {
+    // The output is usually about as big as the input.
+    let size = if options.zstd {
+        0
+    } else {
+        inputs.iter().filter_map(|path| fs::metadata(path).ok())
+              .filter(|metadata| metadata.is_file())
+              .map(|metadata| metadata.len())
+              .sum()
+    };
+    if options.out_of_core {
+        let width = match options.wrap {
+            Some(Wrap::Width(n)) => n,
+            Some(Wrap::Preserve) => unreachable!(),
+            None => LINE_LEN - 1,
+        };
+        return with_output(options, output, size, |output| {
+            for path in inputs {
+                let mut input = open_input(path, options)?;
+                if detect_compression(&mut input)? != Compression::None {
+                    return Err(invalid_input("--out-of-core needs uncompressed input".into()));
+                }
+                if is_fastq(&mut input)? {
+                    return Err(invalid_input("--out-of-core needs FASTA input".into()));
+                }
+                run_out_of_core(input.into_inner(), width, table, output)?;
+            }
+            Ok(())
+        });
     }
 
-    // Print the result.
-    io::stdout().write_all(&buf)
+    run_pipeline(inputs, output, size, options, table, pool)
+}
+
+/// Read, reverse and write on separate threads, so each record can be
+/// reversed while the following ones are read and the earlier ones are still
+/// being written. The queues between them are bounded so a slow stage holds
+/// back the others rather than piling up input in memory.
+#[cfg(not(target_os = "wasi"))]
+fn run_pipeline(inputs: &[PathBuf],
+                output: Option<&Path>,
+                size: u64,
+                options: &Options,
+                table: &[u8; 256],
+                pool: Option<&ThreadPool>) -> io::Result<()> {
+    let (tx, rx) = sync_channel::<Records>(QUEUE_LEN);
+    let (output_tx, output_rx) = sync_channel(QUEUE_LEN);
+    thread::scope(|s| {
+        let writer = s.spawn(move || with_output(options, output, size, |output| {
+            write_batches(output_rx, output)
+        }));
+        let reverser = s.spawn(move || reverse_records(rx, options, table, pool, output_tx));
+        let result = inputs.iter().map(|path| read_records(path, options, tx.clone()))
+                           .collect::<io::Result<()>>();
+        drop(tx);
+        result.and(reverser.join().unwrap()).and(writer.join().unwrap())
+    })
 }
 
-fn main() {
-    run().unwrap()
+/// Without threads, run each stage of the pipeline to the end before the
+/// next one starts.
+#[cfg(target_os = "wasi")]
+fn run_pipeline(inputs: &[PathBuf],
+                output: Option<&Path>,
+                size: u64,
+                options: &Options,
+                table: &[u8; 256],
+                pool: Option<&ThreadPool>) -> io::Result<()> {
+    let (tx, rx) = channel::<Records>();
+    let (output_tx, output_rx) = channel();
+    for path in inputs {
+        read_records(path, options, tx.clone())?;
+    }
+    drop(tx);
+    reverse_records(rx, options, table, pool, output_tx)?;
+    with_output(options, output, size, |output| write_batches(output_rx, output))
+}
+
+fn run() -> io::Result<()> {
+    let options = Options::from_args()?;
+    if options.help {
+        return io::stdout().write_all(USAGE.as_bytes())
+    }
+    if options.version {
+        return write_version(&mut io::stdout());
+    }
+    let table = build_table(&options);
+    let pool = match options.threads {
+        Some(n) => Some(new_pool(n)),
+        None => None,
+    };
+    if let Some(ref path) = options.in_place {
+        return run_in_place(path, options.reverse, &table, pool.as_ref());
+    }
+    if options.separate {
+        for path in &options.inputs {
+            let mut output = path.clone().into_os_string();
+            output.push(".rc");
+            run_files(&[path.clone()], Some(output.as_ref()), &options, &table, pool.as_ref())?;
+        }
+        return Ok(())
+    }
+    // Rather than wait for someone to type in a FASTA file. "-" still reads
+    // the terminal, for anyone who does want to.
+    if options.inputs.is_empty() && io::stdin().is_terminal() {
+        return Err(invalid_input("stdin is a terminal, not the FASTA input; name a file, \
+                                  pipe one in, or see --help".into()));
+    }
+    let stdin = [PathBuf::from("-")];
+    let inputs = if options.inputs.is_empty() { &stdin[..] } else { &options.inputs[..] };
+    if let Some(ref output) = options.output {
+        // Creating the output would truncate an input that is the same file.
+        if let Ok(output) = output.canonicalize() {
+            if inputs.iter().any(|path| path.canonicalize().ok().as_ref() == Some(&output)) {
+                return Err(invalid_input("--output can't be one of the inputs".into()));
+            }
+        }
+    }
+    run_files(inputs, options.output.as_ref().map(|p| p.as_ref()), &options, &table,
+              pool.as_ref())
+}
+
+include!("common/main.rs");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The records of batch `i` of the test input, each a header line and its
+    /// sequence: some wrapped at `LINE_LEN` and reversed in place, and some
+    /// ragged, which are rewrapped.
+    fn records(i: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..3).map(|j| {
+            let header = format!(">batch {} record {}\n", i, j).into_bytes();
+            let len = 1 + (i * 97 + j * 61) % 400;
+            let width = if (i + j) % 2 == 0 { LINE_LEN - 1 } else { 7 + i };
+            let bases: Vec<u8> = (0..len).map(|k| b"ACGTNacgtn"[(k * 7 + i + j) % 10]).collect();
+            let mut seq = vec![];
+            for line in bases.chunks(width) {
+                seq.extend_from_slice(line);
+                seq.push(b'\n');
+            }
+            (header, seq)
+        }).collect()
+    }
+
+    fn batch(i: usize) -> Vec<u8> {
+        records(i).into_iter().flat_map(|(header, seq)| header.into_iter().chain(seq)).collect()
+    }
+
+    /// What reverse_complement should write for batch `i`, worked out one
+    /// record at a time by the simple implementation `--verify` uses.
+    fn expected(i: usize) -> Vec<u8> {
+        let table = build_table(&Options::new());
+        let mut text = vec![];
+        for (header, seq) in records(i) {
+            text.extend_from_slice(&header);
+            reverse_complement_wrapped(&seq, Wrap::Width(LINE_LEN - 1), true, &table, &mut text);
+            text.push(b'\n');
+        }
+        text
+    }
+
+    /// Batch `i` of the test input as the reversing stage hands it on.
+    fn reversed(i: usize) -> Vec<Batch> {
+        let options = Options::new();
+        let table = build_table(&options);
+        let (tx, rx) = sync_channel(QUEUE_LEN);
+        let (output_tx, output_rx) = sync_channel(QUEUE_LEN);
+        let text = batch(i);
+        read_fasta_whole(&text[..], Vec::with_capacity(text.len()), tx).unwrap();
+        reverse_records(rx, &options, &table, None, output_tx).unwrap();
+        output_rx.iter().collect()
+    }
+
+    /// The writer's output, which also decides when the writer gets each
+    /// batch. Each write takes at most `limit` bytes, and then sends the
+    /// writer the next of `ready` batches; once those run out, the rest go and
+    /// the channel is closed. So everything runs on one thread, and the writer
+    /// finds the same batches waiting each time it looks, whenever it's run.
+    struct Feeder {
+        data: Vec<u8>,
+        limit: usize,
+        ready: std::vec::IntoIter<usize>,
+        batches: std::vec::IntoIter<Batch>,
+        tx: Option<SyncSender<Batch>>,
+    }
+
+    impl Feeder {
+        fn send(&mut self, n: usize) {
+            let tx = self.tx.as_ref().unwrap();
+            for batch in self.batches.by_ref().take(n) {
+                tx.send(batch).unwrap();
+            }
+        }
+    }
+
+    impl Write for Feeder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = cmp::min(buf.len(), self.limit);
+            self.data.extend_from_slice(&buf[..n]);
+            if self.tx.is_some() {
+                match self.ready.next() {
+                    Some(ready) => self.send(ready),
+                    None => {
+                        let rest = self.batches.len();
+                        self.send(rest);
+                        self.tx = None;
+                    }
+                }
+            }
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Write `batches` with `write_batches`, the first `ready[0]` of them
+    /// waiting from the start and the rest arriving as `Feeder` sends them.
+    fn write(batches: Vec<Batch>, ready: &[usize], limit: usize) -> Vec<u8> {
+        let (tx, rx) = sync_channel(batches.len());
+        let mut output = Feeder {
+            data: vec![],
+            limit: limit,
+            ready: ready.to_vec().into_iter(),
+            batches: batches.into_iter(),
+            tx: Some(tx),
+        };
+        match output.ready.next() {
+            Some(ready) => output.send(ready),
+            None => output.tx = None,
+        }
+        write_batches(rx, &mut output).unwrap();
+        output.data
+    }
+
+    /// Every way of splitting `n` into a list of whole numbers above 0.
+    fn compositions(n: usize) -> Vec<Vec<usize>> {
+        if n == 0 {
+            return vec![vec![]]
+        }
+        let mut all = vec![];
+        for first in 1..n + 1 {
+            for mut rest in compositions(n - first) {
+                rest.insert(0, first);
+                all.push(rest);
+            }
+        }
+        all
+    }
+
+    /// Splitting off either end gives back exactly the `n` items asked for,
+    /// or all of them when `n` is longer than the slice, and leaves the rest.
+    #[test]
+    fn split_off_partitions_slice() {
+        let len = 3 * LINE_LEN + 5;
+        let original: Vec<usize> = (0..len).collect();
+        let counts = [0, 1, LINE_LEN - 1, LINE_LEN, LINE_LEN + 1, 2 * LINE_LEN, len - 1, len,
+                      len + 1, usize::max_value()];
+        for &n in &counts {
+            let m = cmp::min(n, len);
+
+            let mut data = original.clone();
+            let mut rest = &mut data[..];
+            let left = rest.split_off_left(n).to_vec();
+            assert_eq!(left, &original[..m], "left {}", n);
+            assert_eq!(rest, &original[m..], "left {}", n);
+
+            let mut data = original.clone();
+            let mut rest = &mut data[..];
+            let right = rest.split_off_right(n).to_vec();
+            assert_eq!(right, &original[len - m..], "right {}", n);
+            assert_eq!(rest, &original[..len - m], "right {}", n);
+        }
+    }
+
+    /// Each code complements to the one for the pairing bases, written out
+    /// here rather than taken from `IUPAC_COMPLEMENTS`, so a pair swapped
+    /// there is caught even when complementing twice still gives the input.
+    #[test]
+    fn complements_pair_iupac_codes() {
+        let pairs = [
+            (b'A', b'T'), (b'C', b'G'), (b'G', b'C'), (b'T', b'A'), (b'U', b'A'),
+            (b'M', b'K'), (b'K', b'M'), (b'R', b'Y'), (b'Y', b'R'), (b'W', b'W'),
+            (b'S', b'S'), (b'B', b'V'), (b'V', b'B'), (b'D', b'H'), (b'H', b'D'),
+            (b'N', b'N'),
+        ];
+        let normal = build_table(&Options::new());
+        let mut options = Options::new();
+        options.case = Case::Preserve;
+        let preserve = build_table(&options);
+        for &(code, complement) in &pairs {
+            let lower = code.to_ascii_lowercase();
+            assert_eq!(normal[code as usize], complement, "{}", code as char);
+            assert_eq!(normal[lower as usize], complement, "{}", lower as char);
+            assert_eq!(preserve[code as usize], complement, "{}", code as char);
+            assert_eq!(preserve[lower as usize], complement.to_ascii_lowercase(), "{}",
+                       lower as char);
+        }
+        assert_eq!(IUPAC_COMPLEMENTS.len(), pairs.len());
+        // Anything that isn't a code is left as it is.
+        for &b in b"Xx-*.EJOZ" {
+            assert_eq!(normal[b as usize], b, "{}", b as char);
+            assert_eq!(preserve[b as usize], b, "{}", b as char);
+        }
+    }
+
+    /// However many batches the writer finds waiting each time it looks, and
+    /// however little of its output each write takes, the records come out in
+    /// the order they went in.
+    #[test]
+    fn output_keeps_input_order() {
+        const BATCHES: usize = 5;
+        let expected: Vec<u8> = (0..BATCHES).flat_map(expected).collect();
+        for ready in compositions(BATCHES) {
+            for &limit in &[1 << 20, 500, 1] {
+                let batches = (0..BATCHES).flat_map(reversed).collect();
+                let output = write(batches, &ready, limit);
+                assert!(output == expected,
+                        "out of order with {:?} batches ready at a time, {} bytes a write",
+                        ready, limit);
+            }
+        }
+    }
 }
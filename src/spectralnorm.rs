@@ -7,8 +7,13 @@
 
 #![allow(non_snake_case)]
 
+
+use std::io::{self, Write};
+use std::ops::{Add, Div, Mul};
 use std::thread;
 
+include!("common/alloc.rs");
+
 // As std::simd::f64x2 etc. are unstable, we provide a similar interface,
 // expecting llvm to autovectorize its usage.
 #[allow(non_camel_case_types)]
@@ -32,93 +37,188 @@ impl std::ops::Div for usizex2 {
         usizex2(self.0 / rhs.0, self.1 / rhs.1)
     }
 }
-impl From<usizex2> for f64x2 {
-    fn from(i: usizex2) -> f64x2 {
-        f64x2(i.0 as f64, i.1 as f64)
+impl<T: Float> From<usizex2> for floatx2<T> {
+    fn from(i: usizex2) -> floatx2<T> {
+        floatx2(T::from_usize(i.0), T::from_usize(i.1))
     }
 }
 
 #[allow(non_camel_case_types)]
-struct f64x2(f64, f64);
-impl std::ops::Add for f64x2 {
+struct floatx2<T>(T, T);
+impl<T: Float> std::ops::Add for floatx2<T> {
     type Output = Self;
     fn add(self, rhs: Self) -> Self {
-        f64x2(self.0 + rhs.0, self.1 + rhs.1)
+        floatx2(self.0 + rhs.0, self.1 + rhs.1)
     }
 }
-impl std::ops::Div for f64x2 {
+impl<T: Float> std::ops::Div for floatx2<T> {
     type Output = Self;
     fn div(self, rhs: Self) -> Self {
-        f64x2(self.0 / rhs.0, self.1 / rhs.1)
+        floatx2(self.0 / rhs.0, self.1 / rhs.1)
     }
 }
 
-fn main() {
-    let n = std::env::args_os().nth(1)
-        .and_then(|s| s.into_string().ok())
+/// The precisions the kernel can run in: f64 for the benchmark, or f32 with
+/// `--f32`.
+trait Float: Copy + Send + Sync + 'static +
+             Add<Output = Self> + Mul<Output = Self> + Div<Output = Self> {
+    fn from_usize(n: usize) -> Self;
+    fn from_f64(x: f64) -> Self;
+    fn sqrt(self) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+impl Float for f64 {
+    fn from_usize(n: usize) -> f64 { n as f64 }
+    fn from_f64(x: f64) -> f64 { x }
+    fn sqrt(self) -> f64 { f64::sqrt(self) }
+    fn to_f64(self) -> f64 { self }
+}
+
+impl Float for f32 {
+    fn from_usize(n: usize) -> f32 { n as f32 }
+    fn from_f64(x: f64) -> f32 { x as f32 }
+    fn sqrt(self) -> f32 { f32::sqrt(self) }
+    fn to_f64(self) -> f64 { self as f64 }
+}
+
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: spectralnorm [--f32] [--iterations K] [--threads N] [N]
+
+Approximate the spectral norm of the benchmark's infinite matrix from its top
+left N by N corner (default 100).
+
+  --f32           compute in f32 rather than f64
+  --iterations K  do K rounds of the power method rather than 10
+  --threads N     use N threads rather than one for each CPU
+  -h, --help      print this message
+  --version       print how this was built
+";
+
+include!("common/main.rs");
+
+fn run() -> io::Result<()> {
+    let mut n = 100;
+    let mut f32 = false;
+    // The benchmark does 10 rounds of the power method.
+    let mut iterations = 10;
+    // RAYON_NUM_THREADS sets the default, as for reverse_complement.
+    let mut threads = std::env::var("RAYON_NUM_THREADS").ok()
         .and_then(|n| n.parse().ok())
-        .unwrap_or(100);
-    let answer = spectralnorm(n);
-    println!("{:.9}", answer);
-}
-
-fn spectralnorm(n: usize) -> f64 {
-    assert!(n % 2 == 0, "only even lengths are accepted");
-    let mut u = vec![1.0; n];
-    let mut v = vec![0.0; n];
-    let mut tmp = vec![0.0; n];
-    for _ in 0..10 {
-        mult_AtAv(&u, &mut v, &mut tmp);
-        mult_AtAv(&v, &mut u, &mut tmp);
+        .filter(|&n| n > 0);
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--f32") => f32 = true,
+            Some("--iterations") => {
+                iterations = args.next().as_ref().and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&k| k > 0)
+                    .ok_or_else(|| invalid_input("--iterations needs a positive count".into()))?;
+            }
+            Some("--threads") => {
+                threads = Some(args.next().as_ref()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid_input("--threads needs a positive number".into()))?);
+            }
+            Some("-h") | Some("--help") => return io::stdout().write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut io::stdout()),
+            _ => n = arg.to_str().and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_input(format!("unknown argument {:?}; see --help", arg)))?,
+        }
+    }
+    if n % 2 != 0 {
+        return Err(invalid_input("only even lengths are accepted".into()));
+    }
+    let threads = threads.unwrap_or_else(|| {
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    });
+    let answer = if f32 {
+        spectralnorm::<f32>(n, iterations, threads)
+    } else {
+        spectralnorm::<f64>(n, iterations, threads)
+    };
+    writeln!(io::stdout(), "{:.9}", answer)
+}
+
+fn spectralnorm<T: Float>(n: usize, iterations: usize, threads: usize) -> f64 {
+    let (zero, one) = (T::from_usize(0), T::from_usize(1));
+    let mut u = vec![one; n];
+    let mut v = vec![zero; n];
+    let mut tmp = vec![zero; n];
+    for i in 0..iterations {
+        if i > 0 {
+            rescale(&mut u);
+        }
+        mult_AtAv(&u, &mut v, &mut tmp, threads);
+        mult_AtAv(&v, &mut u, &mut tmp, threads);
+    }
+    (dot(&u, &v) / dot(&v, &v)).sqrt().to_f64()
+}
+
+/// `u` grows by about the norm squared every iteration, which overflows f32
+/// after a few dozen. Scaling by a power of two is exact, and the answer only
+/// depends on the ratio of `u` and `v`, so this doesn't change it; the
+/// benchmark's 10 iterations never get big enough to need it anyway.
+fn rescale<T: Float>(u: &mut [T]) {
+    let max = u.iter().fold(0., |max: f64, &x| max.max(x.to_f64().abs()));
+    if max > 4294967296. {
+        let scale = T::from_f64(2f64.powi(-(max.log2().floor() as i32)));
+        for x in u {
+            *x = *x * scale;
+        }
     }
-    (dot(&u, &v) / dot(&v, &v)).sqrt()
 }
 
-fn mult_AtAv(v: &[f64], out: &mut [f64], tmp: &mut [f64]) {
-    mult_Av(v, tmp);
-    mult_Atv(tmp, out);
+fn mult_AtAv<T: Float>(v: &[T], out: &mut [T], tmp: &mut [T], threads: usize) {
+    mult_Av(v, tmp, threads);
+    mult_Atv(tmp, out, threads);
 }
 
-fn mult_Av(v: &[f64], out: &mut [f64]) {
-    parallel(out, |start, out| mult(v, out, start, Ax2));
+fn mult_Av<T: Float>(v: &[T], out: &mut [T], threads: usize) {
+    parallel(out, threads, |start, out| mult(v, out, start, Ax2));
 }
 
-fn mult_Atv(v: &[f64], out: &mut [f64]) {
-    parallel(out, |start, out| mult(v, out, start, |i, j| Ax2(j, i)));
+fn mult_Atv<T: Float>(v: &[T], out: &mut [T], threads: usize) {
+    parallel(out, threads, |start, out| mult(v, out, start, |i, j| Ax2(j, i)));
 }
 
-fn mult<F>(v: &[f64], out: &mut [f64], start: usize, a: F)
-           where F: Fn(usizex2, usizex2) -> f64x2 {
+fn mult<T, F>(v: &[T], out: &mut [T], start: usize, a: F)
+              where T: Float, F: Fn(usizex2, usizex2) -> floatx2<T> {
+    let zero = T::from_usize(0);
     for (i, slot) in out.iter_mut().enumerate().map(|(i, s)| (i + start, s)) {
-        let mut sum = f64x2(0.0, 0.0);
+        let mut sum = floatx2(zero, zero);
         for (j, chunk) in v.chunks(2).enumerate().map(|(j, s)| (2 * j, s)) {
-            let top = f64x2(chunk[0], chunk[1]);
+            let top = floatx2(chunk[0], chunk[1]);
             let bot = a(usizex2(i, i), usizex2(j, j+1));
             sum = sum + top / bot;
         }
-        let f64x2(a, b) = sum;
+        let floatx2(a, b) = sum;
         *slot = a + b;
     }
 }
 
-fn Ax2(i: usizex2, j: usizex2) -> f64x2 {
+fn Ax2<T: Float>(i: usizex2, j: usizex2) -> floatx2<T> {
     ((i + j) * (i + j + usizex2(1, 1)) / usizex2(2, 2) + i + usizex2(1, 1)).into()
 }
 
-fn dot(v: &[f64], u: &[f64]) -> f64 {
-    v.iter().zip(u.iter()).map(|(a, b)| *a * *b).fold(0., |acc, i| acc + i)
+fn dot<T: Float>(v: &[T], u: &[T]) -> T {
+    v.iter().zip(u.iter()).map(|(a, b)| *a * *b).fold(T::from_usize(0), |acc, i| acc + i)
 }
 
 struct Racy<T>(T);
 unsafe impl<T: 'static> Send for Racy<T> {}
 
-// Executes a closure in parallel over the given mutable slice. The closure `f`
-// is run in parallel and yielded the starting index within `v` as well as a
-// sub-slice of `v`.
-fn parallel<'a, T, F>(v: &mut [T], ref f: F)
+// Executes a closure in parallel over the given mutable slice, split between
+// `threads` threads. The closure `f` is run in parallel and yielded the
+// starting index within `v` as well as a sub-slice of `v`.
+fn parallel<'a, T, F>(v: &mut [T], threads: usize, ref f: F)
     where T: 'static + Send + Sync,
           F: Fn(usize, &mut [T]) + Sync {
-    let size = v.len() / 4 + 1;
+    let size = v.len() / threads + 1;
     let jhs = v.chunks_mut(size).enumerate().map(|(i, chunk)| {
         // Need to convert `f` and `chunk` to something that can cross the task
         // boundary.
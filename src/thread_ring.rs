@@ -4,10 +4,16 @@
 // contributed by the Rust Project Developers
 // contributed by TeXitoi
 
+
+use std::io::{self, Write};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread;
 
-fn start(n_tasks: i32, token: i32) {
+include!("common/alloc.rs");
+
+/// Pass `token` round a ring of `n_tasks` threads, and return the id of the
+/// one holding it when it runs out.
+fn start(n_tasks: i32, token: i32) -> i32 {
     let (tx, mut rx) = channel();
     tx.send(token).unwrap();
     let mut guards = Vec::with_capacity(n_tasks as usize);
@@ -17,28 +23,55 @@ fn start(n_tasks: i32, token: i32) {
         guards.push(thread::spawn(move|| roundtrip(i, tx, cur_rx)));
     }
     guards.push(thread::spawn(move|| roundtrip(1, tx, rx)));
-    for g in guards { g.join().unwrap(); }
+    let mut last = 0;
+    for g in guards {
+        if let Some(id) = g.join().unwrap() { last = id; }
+    }
+    last
 }
 
-fn roundtrip(id: i32, tx: Sender<i32>, rx: Receiver<i32>) {
+fn roundtrip(id: i32, tx: Sender<i32>, rx: Receiver<i32>) -> Option<i32> {
     for token in rx.iter() {
         if token == 1 {
-            println!("{}", id);
-            break;
+            return Some(id);
         }
         tx.send(token - 1).unwrap();
     }
+    None
 }
 
-fn main() {
-    let args = &mut std::env::args_os();
-    let token = args.skip(1).next()
-        .and_then(|s| s.into_string().ok())
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(1000);
-    let n_tasks = args.next()
-        .and_then(|s| s.into_string().ok())
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(503);
-    start(n_tasks, token);
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: thread_ring [TOKEN [THREADS]]
+
+Pass a token TOKEN times (default 1000) around a ring of THREADS threads
+(default 503), and print the number of the thread holding it at the end.
+";
+
+include!("common/main.rs");
+
+fn run() -> io::Result<()> {
+    let mut numbers = vec![];
+    for arg in std::env::args_os().skip(1) {
+        match arg.to_str() {
+            Some("-h") | Some("--help") => return io::stdout().write_all(USAGE.as_bytes()),
+            Some("--version") => return write_version(&mut io::stdout()),
+            _ => numbers.push(arg.to_str().and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_input(format!("unknown argument {:?}; see --help", arg)))?),
+        }
+    }
+    if numbers.len() > 2 {
+        return Err(invalid_input("too many arguments; see --help".into()));
+    }
+    let token = numbers.get(0).cloned().unwrap_or(1000);
+    let n_tasks = numbers.get(1).cloned().unwrap_or(503);
+    // The token is passed until it counts down to 1, so it has to start there.
+    if token < 1 {
+        return Err(invalid_input("TOKEN needs a positive number".into()));
+    }
+    if n_tasks < 1 {
+        return Err(invalid_input("THREADS needs a positive number".into()));
+    }
+    let last = start(n_tasks, token);
+    writeln!(io::stdout(), "{}", last)
 }